@@ -0,0 +1,164 @@
+//! Mixer throughput: frames per second with `sample()` (one frame at a time) and `render()` (spans)
+//!
+//! Both methods run the channel code of this version: the ratio measures span mixing against
+//! per-frame mixing, not the gain over the `sample()` of an earlier version.
+//!
+//! `cargo run --release --example bench_mixer --features std [--features import] [module files...]`
+use std::time::Instant;
+use xmrs::prelude::*;
+use xmrsplayer::prelude::*;
+
+const RATE: f32 = 48000.0;
+/// Rendered frames for each module and method
+const FRAMES: usize = 48000 * 60;
+
+/// `channels` channels, a note every `every` rows, some vibrato and volume slides
+///
+/// One instrument for each loop type: 8 bits forward, 16 bits ping-pong, 16 bits stereo without loop.
+fn synthetic(channels: usize, every: usize, frequency_type: FrequencyType) -> Module {
+    let len = 8192;
+    let wave = |i: usize| (i as f32 * 0.07).sin() * 0.8 + (i as f32 * 0.013).sin() * 0.2;
+    let samples = [
+        (
+            LoopType::Forward,
+            SampleDataType::Mono8((0..len).map(|i| (wave(i) * 127.0) as i8).collect()),
+        ),
+        (
+            LoopType::PingPong,
+            SampleDataType::Mono16((0..len).map(|i| (wave(i) * 32767.0) as i16).collect()),
+        ),
+        (
+            LoopType::No,
+            SampleDataType::Stereo16(
+                (0..len * 2)
+                    .map(|i| (wave(i / 2) * 30000.0) as i16)
+                    .collect(),
+            ),
+        ),
+    ];
+
+    let mut module = Module {
+        frequency_type,
+        ..Default::default()
+    };
+    for (flags, data) in samples {
+        let mut instr = InstrDefault::default();
+        instr.sample.push(Sample {
+            name: "".into(),
+            loop_start: len as u32 / 4,
+            loop_length: len as u32 / 2,
+            volume: 1.0,
+            finetune: 0.0,
+            flags,
+            panning: 0.5,
+            relative_note: 0,
+            data,
+        });
+        module.instrument.push(Instrument {
+            name: "".into(),
+            instr_type: InstrumentType::Default(instr),
+            muted: false,
+        });
+    }
+
+    let notes = [Note::C4, Note::E4, Note::G4, Note::C5, Note::A3, Note::D5];
+    for p in 0..4 {
+        let mut pattern: Pattern = vec![vec![PatternSlot::default(); channels]; 64];
+        for (r, row) in pattern.iter_mut().enumerate() {
+            for (c, slot) in row.iter_mut().enumerate() {
+                if (r + c) % every == 0 {
+                    slot.note = notes[(r + c + p) % notes.len()];
+                    slot.instrument = 1 + ((c + p) % 3) as u8;
+                } else {
+                    // Vibrato or volume slide
+                    (slot.effect_type, slot.effect_parameter) =
+                        [(0x4, 0x46), (0xA, 0x01), (0, 0)][c % 3];
+                }
+            }
+        }
+        module.pattern.push(pattern);
+        module.pattern_order.push(p);
+    }
+    module
+}
+
+#[cfg(feature = "import")]
+fn load(filename: &str) -> Option<Module> {
+    use xmrs::amiga::amiga_module::AmigaModule;
+    use xmrs::s3m::s3m_module::S3mModule;
+    use xmrs::xm::xmmodule::XmModule;
+
+    let contents = std::fs::read(filename).ok()?;
+    match filename.split('.').last()?.to_lowercase().as_str() {
+        "xm" => Some(XmModule::load(&contents).ok()?.to_module()),
+        "mod" => Some(AmigaModule::load(&contents).ok()?.to_module()),
+        "s3m" => Some(S3mModule::load(&contents).ok()?.to_module()),
+        _ => None,
+    }
+}
+
+fn player(module: &Module) -> XmrsPlayer<'_> {
+    let mut player = XmrsPlayer::new(module, RATE, false);
+    player.set_max_loop_count(0);
+    player
+}
+
+fn bench(name: &str, module: &Module) {
+    let mut reference = Vec::with_capacity(FRAMES);
+    let mut p = player(module);
+    let start = Instant::now();
+    while reference.len() < FRAMES {
+        match p.sample(true) {
+            Some(s) => reference.push(s),
+            None => break,
+        }
+    }
+    let before = reference.len() as f64 / start.elapsed().as_secs_f64();
+
+    let mut spans = vec![(0.0, 0.0); reference.len()];
+    let mut p = player(module);
+    let start = Instant::now();
+    let mut written = 0;
+    for chunk in spans.chunks_mut(1024) {
+        written += p.render(chunk);
+    }
+    let after = written as f64 / start.elapsed().as_secs_f64();
+
+    let identical = written == reference.len()
+        && spans
+            .iter()
+            .zip(&reference)
+            .all(|(a, b)| a.0.to_bits() == b.0.to_bits() && a.1.to_bits() == b.1.to_bits());
+    println!(
+        "{:<24} {:>3} ch  sample(): {:>10.0} frames/s  render(): {:>10.0} frames/s  render/sample x{:.2}  identical: {}",
+        name,
+        module.get_num_channels(),
+        before,
+        after,
+        after / before,
+        identical
+    );
+}
+
+fn main() {
+    bench(
+        "4 ch, Amiga",
+        &synthetic(4, 4, FrequencyType::AmigaFrequencies),
+    );
+    bench(
+        "8 ch, linear",
+        &synthetic(8, 2, FrequencyType::LinearFrequencies),
+    );
+    bench(
+        "32 ch, linear",
+        &synthetic(32, 8, FrequencyType::LinearFrequencies),
+    );
+
+    #[cfg(feature = "import")]
+    for filename in std::env::args().skip(1) {
+        match load(&filename) {
+            Some(module) => bench(&filename, &module),
+            None => println!("{}: can't load", filename),
+        }
+    }
+}
@@ -8,6 +8,7 @@ use xmrs::prelude::*;
 use xmrs::s3m::s3m_module::S3mModule;
 use xmrs::xm::xmmodule::XmModule;
 
+use xmrsplayer::loudness::{LoudnessMeter, LoudnessReport};
 use xmrsplayer::prelude::*;
 
 #[cfg(feature = "sid")]
@@ -23,6 +24,18 @@ struct Cli {
     #[arg(short = 'o', long, value_name = "output filename")]
     output: Option<String>,
 
+    /// Export song as a Standard MIDI File
+    #[arg(long, value_name = "midi filename")]
+    export_midi: Option<String>,
+
+    /// Amiga stereo separation, from 0.0 (mono) to 1.0 (hard LRRL pan)
+    #[arg(long, default_value = "1.0")]
+    separation: f32,
+
+    /// Set amplification to reach a loudness target in LUFS (EBU R128 uses -23.0)
+    #[arg(long, value_name = "LUFS")]
+    target_lufs: Option<f64>,
+
     /// Choose amplification
     #[arg(short = 'a', long, default_value = "10.0")]
     amplification: f32,
@@ -89,6 +102,9 @@ fn sid_test_player(cli: &Cli) {
         cli.speed,
         false,
         cli.output.clone(),
+        cli.export_midi.clone(),
+        cli.separation,
+        cli.target_lufs,
     );
 }
 
@@ -132,6 +148,9 @@ fn main() -> Result<(), std::io::Error> {
                                 cli.speed,
                                 cli.historical,
                                 cli.output.clone(),
+                                cli.export_midi.clone(),
+                                cli.separation,
+                                cli.target_lufs,
                             );
                         }
                         Err(e) => {
@@ -158,6 +177,9 @@ fn main() -> Result<(), std::io::Error> {
                                 cli.speed,
                                 false,
                                 cli.output.clone(),
+                                cli.export_midi.clone(),
+                                cli.separation,
+                                cli.target_lufs,
                             );
                         }
                         Err(e) => {
@@ -184,6 +206,9 @@ fn main() -> Result<(), std::io::Error> {
                                 cli.speed,
                                 false,
                                 cli.output.clone(),
+                                cli.export_midi.clone(),
+                                cli.separation,
+                                cli.target_lufs,
                             );
                         }
                         Err(e) => {
@@ -211,7 +236,33 @@ fn play_music(
     speed: u16,
     historical: bool,
     output: Option<String>,
+    export_midi: Option<String>,
+    separation: f32,
+    target_lufs: Option<f64>,
 ) {
+    // try to detect FT2 to play historical bugs
+    let is_ft2 = historical
+        || module.comment == "FastTracker v2.00 (1.02)"
+        || module.comment == "FastTracker v2.00 (1.03)"
+        || module.comment == "FastTracker v2.00 (1.04)";
+
+    if let Some(export_midi) = export_midi {
+        println!("writing {}...", export_midi);
+        let mut player = XmrsPlayer::new(module, 44100.0, is_ft2);
+        player.set_stereo_separation(separation);
+        if ch != 0 {
+            player.mute_all(true);
+            player.set_mute_channel((ch - 1).into(), false);
+        }
+        player.set_max_loop_count(loops);
+        player.goto(position, 0, speed);
+        let smf = xmrsplayer::midi_export::export_midi(&mut player);
+        if let Err(e) = std::fs::write(export_midi, smf) {
+            println!("{:?}", e);
+        }
+        return;
+    }
+
     let host = cpal::default_host();
     let device = host
         .default_output_device()
@@ -221,11 +272,6 @@ fn play_music(
         .default_output_config()
         .expect("failed to get default output config");
     let sample_rate = config.sample_rate();
-    // try to detect FT2 to play historical bugs
-    let is_ft2 = historical
-        || module.comment == "FastTracker v2.00 (1.02)"
-        || module.comment == "FastTracker v2.00 (1.03)"
-        || module.comment == "FastTracker v2.00 (1.04)";
 
     let player = Arc::new(Mutex::new(XmrsPlayer::new(
         module,
@@ -236,6 +282,14 @@ fn play_music(
     {
         let mut player_lock = player.lock().unwrap();
         player_lock.amplification = amplification;
+        player_lock.set_stereo_separation(separation);
+        if let Some(target) = target_lufs {
+            let report = player_lock.auto_gain(target);
+            println!(
+                "loudness {:.1} LUFS, range {:.1} LU, peak {:.3}, amplification set to {:.3}",
+                report.integrated, report.range, report.sample_peak, player_lock.amplification
+            );
+        }
         if debug {
             println!("Debug on");
             if is_ft2 {
@@ -244,8 +298,7 @@ fn play_music(
         }
         player_lock.debug(debug);
         if ch != 0 {
-            player_lock.mute_all(true);
-            player_lock.set_mute_channel((ch - 1).into(), false);
+            player_lock.console.set_solo((ch - 1).into(), true);
         }
         player_lock.set_max_loop_count(loops);
         player_lock.goto(position, 0, speed);
@@ -366,12 +419,49 @@ fn write_wave(
     amp.lock().unwrap().set_max_loop_count(1);
     let player_clone = Arc::clone(&amp);
     let mut player_lock = player_clone.lock().unwrap();
+    let mut meter = LoudnessMeter::new(spec.sample_rate as f32);
 
-    for sample in player_lock.by_ref() {
-        let sample_i16 = (sample * 32767.0).round() as i16;
-        writer.write_sample(sample_i16)?;
+    while let Some((left, right)) = player_lock.sample(true) {
+        meter.push((left, right));
+        for sample in [left, right] {
+            let sample_i16 = (sample * 32767.0).round() as i16;
+            writer.write_sample(sample_i16)?;
+        }
     }
 
     writer.finalize()?;
+    append_replay_gain(output_file, &meter.report())?;
     Ok(())
 }
+
+/// Add ReplayGain tags as an ID3v2.3 chunk at the end of the wave file
+fn append_replay_gain(output_file: &str, report: &LoudnessReport) -> std::io::Result<()> {
+    let mut frames: Vec<u8> = vec![];
+    for (key, value) in report.replay_gain_tags() {
+        // TXXX: encoding, description, 0, value
+        let mut content = vec![0u8];
+        content.extend_from_slice(key.as_bytes());
+        content.push(0);
+        content.extend_from_slice(value.as_bytes());
+        frames.extend_from_slice(b"TXXX");
+        frames.extend_from_slice(&(content.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&[0, 0]);
+        frames.extend_from_slice(&content);
+    }
+    let size = frames.len() as u32;
+    let mut id3: Vec<u8> = b"ID3\x03\x00\x00".to_vec();
+    // Syncsafe size
+    id3.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8));
+    id3.extend_from_slice(&frames);
+    if id3.len() % 2 == 1 {
+        id3.push(0);
+    }
+
+    let mut wave = std::fs::read(output_file)?;
+    wave.extend_from_slice(b"id3 ");
+    wave.extend_from_slice(&(id3.len() as u32).to_le_bytes());
+    wave.extend_from_slice(&id3);
+    let riff_size = (wave.len() - 8) as u32;
+    wave[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    std::fs::write(output_file, wave)
+}
@@ -13,12 +13,61 @@ use crate::effect_toneportamento::EffectTonePortamento;
 use crate::effect_vibrato_tremolo::EffectVibratoTremolo;
 use crate::effect_volume_panning_slide::EffectVolumePanningSlide;
 use crate::historical_helper::HistoricalHelper;
+use crate::midi::MidiSink;
+use crate::pan_law::{PanLaw, VolumeCurve};
+use crate::sample_source::SourceRef;
+use crate::state_midi::StateMidi;
 use crate::triggerkeep::*;
 
 use crate::helper::*;
-use crate::state_instr_default::StateInstrDefault;
+use crate::state_instr_default::{InstrRef, StateInstrDefault};
+use alloc::{sync::Arc, vec, vec::Vec};
 use xmrs::prelude::*;
 
+/// What a channel is playing, for visualizers
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelInfo {
+    /// Last note played, its `Debug` format is the note name (like `C-4`)
+    pub note: Note,
+    /// Instrument number (index in `module.instrument`), None if no instrument
+    pub instrument: Option<usize>,
+    /// Sample index in the instrument
+    pub sample: Option<usize>,
+    /// Playback frequency in Hz, after effects, 0.0 if silent
+    pub frequency: f32,
+    /// Volume after effects, envelopes and fadeout: 0.0 to 1.0
+    pub volume: f32,
+    /// Panning after effects and envelope: 0.0 (left) to 1.0 (right)
+    pub panning: f32,
+    /// Sample playback position in frames
+    pub position: f32,
+    /// Slot played at current row
+    pub slot: PatternSlot,
+    pub muted: bool,
+}
+
+/// Initial settings of a channel, applied by `XmrsPlayer::new()` and `XmrsPlayer::goto()`
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelDefault {
+    /// Channel panning: 0.0 (left) to 1.0 (right), kept across notes (MOD, S3M, IT).
+    /// None to use sample panning at each note (XM).
+    pub panning: Option<f32>,
+    /// Channel volume: 0.0 to 1.0
+    pub volume: f32,
+    /// A disabled channel is muted
+    pub enabled: bool,
+}
+
+impl Default for ChannelDefault {
+    fn default() -> Self {
+        Self {
+            panning: None,
+            volume: 1.0,
+            enabled: true,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Channel<'a> {
     module: &'a Module,
@@ -57,6 +106,28 @@ pub struct Channel<'a> {
     vibrato: EffectVibratoTremolo,
 
     semitone: bool,
+    /// Pitch bend in semitones, set from outside of pattern data
+    pitch_bend: f32,
+    /// Channel volume, multiplies note volume: 0.0 to 1.0
+    channel_volume: f32,
+    /// Panning is kept across notes instead of using sample panning
+    channel_panning: bool,
+    pan_law: PanLaw,
+    volume_curve: VolumeCurve,
+    /// Nearest neighbour instead of linear interpolation
+    nearest: bool,
+
+    /// Integer copies of `actual_volume` (Q15) and console gains (Q23, below 256.0), for the integer mixing path
+    ///
+    /// Gains are ramped with 23 fractional bits to follow the float ramp.
+    #[cfg(feature = "fixed")]
+    actual_volume_q15: [i32; 2],
+    #[cfg(feature = "fixed")]
+    mix_gain_q23: [i32; 2],
+    #[cfg(feature = "fixed")]
+    mix_target_q23: [i32; 2],
+    #[cfg(feature = "fixed")]
+    mix_ramp_q23: i32,
 
     note_delay_param: u8,
     /// Where to restart a E6y loop
@@ -68,8 +139,30 @@ pub struct Channel<'a> {
     tremor_on: bool,
 
     pub muted: bool,
+    /// Disabled by `ChannelDefault`, muted too
+    pub(crate) disabled: bool,
 
     actual_volume: [f32; 2],
+    /// Volume after tremolo, envelope and fadeout, before panning
+    final_volume: f32,
+    /// Panning after panning envelope
+    final_panning: f32,
+    /// Last note set
+    played_note: Note,
+
+    /// A new note was started on this tick, cleared by the player at the end of each tick
+    pub(crate) triggered: bool,
+    midi: StateMidi,
+
+    /// Instruments replaced while playing
+    instrument_override: Vec<(usize, Arc<InstrDefault>)>,
+    /// Sample frames read from another source: (instrument, sample, source)
+    sample_source: Vec<(usize, usize, SourceRef<'a>)>,
+
+    /// Console gains, going to `mix_target` by `mix_ramp` each sample
+    mix_gain: [f32; 2],
+    mix_target: [f32; 2],
+    mix_ramp: f32,
 }
 
 impl<'a> Channel<'a> {
@@ -101,13 +194,38 @@ impl<'a> Channel<'a> {
             volume_slide: EffectVolumePanningSlide::default(),
             volume_slide_tick0: EffectVolumePanningSlide::default(),
             semitone: false,
+            pitch_bend: 0.0,
+            channel_volume: 1.0,
+            channel_panning: false,
+            pan_law: PanLaw::default(),
+            volume_curve: VolumeCurve::default(),
+            nearest: false,
+            #[cfg(feature = "fixed")]
+            actual_volume_q15: [0, 0],
+            #[cfg(feature = "fixed")]
+            mix_gain_q23: [1 << 23, 1 << 23],
+            #[cfg(feature = "fixed")]
+            mix_target_q23: [1 << 23, 1 << 23],
+            #[cfg(feature = "fixed")]
+            mix_ramp_q23: 1 << 23,
             note_delay_param: 0,
             pattern_loop_origin: 0,
             pattern_loop_count: 0,
             tremor_param: 0,
             tremor_on: false,
             muted: false,
+            disabled: false,
             actual_volume: [0.0, 0.0],
+            final_volume: 0.0,
+            final_panning: 0.5,
+            played_note: Note::None,
+            triggered: false,
+            midi: StateMidi::default(),
+            instrument_override: vec![],
+            sample_source: vec![],
+            mix_gain: [1.0, 1.0],
+            mix_target: [1.0, 1.0],
+            mix_ramp: 1.0,
         }
     }
 
@@ -117,7 +235,7 @@ impl<'a> Channel<'a> {
         } else {
             false
         };
-        self.muted || midi_mute
+        self.muted || self.disabled || midi_mute
     }
 
     fn cut_note(&mut self) {
@@ -125,6 +243,12 @@ impl<'a> Channel<'a> {
         self.volume = 0.0;
     }
 
+    /// Stop the voice now, without release or fadeout
+    pub(crate) fn cut(&mut self) {
+        self.cut_note();
+        self.instr = None;
+    }
+
     fn key_off_historical(&mut self, tick: u16) {
         if let Some(i) = &mut self.instr {
             i.key_off();
@@ -179,19 +303,29 @@ impl<'a> Channel<'a> {
                     self.volume = instr.volume;
                 }
 
-                // TODO
-                self.panning = instr.panning;
+                if !self.channel_panning {
+                    self.panning = instr.panning;
+                }
 
                 if !contains(flags, TRIGGER_KEEP_PERIOD) {
                     self.period = self.period_helper.note_to_period(self.note);
-                    instr.update_frequency(self.period, 0.0, self.vibrato.value(), self.semitone);
+                    instr.update_frequency(
+                        self.period,
+                        self.pitch_bend,
+                        self.vibrato.value(),
+                        self.semitone,
+                    );
+
+                    if !contains(flags, TRIGGER_KEEP_SAMPLE_POSITION) {
+                        self.triggered = true;
+                    }
                 }
             }
             None => {}
         }
     }
 
-    fn tickn_update_instr(&mut self) {
+    pub(crate) fn tickn_update_instr(&mut self) {
         match &mut self.instr {
             Some(instr) => {
                 let panning: f32 = self.panning
@@ -203,17 +337,25 @@ impl<'a> Channel<'a> {
                 if !self.tremor_on {
                     volume = self.volume + self.tremolo.value();
                     clamp(&mut volume);
-                    volume *= instr.get_volume();
+                    volume *= instr.get_volume(self.volume_curve) * self.channel_volume;
                 }
 
-                self.actual_volume[0] = volume * panning.sqrt();
-                self.actual_volume[1] = volume * (1.0 - panning).sqrt();
+                self.final_volume = volume;
+                self.final_panning = panning;
+                let gains = self.pan_law.gains(panning);
+                self.actual_volume[0] = volume * gains[0];
+                self.actual_volume[1] = volume * gains[1];
+                #[cfg(feature = "fixed")]
+                {
+                    self.actual_volume_q15[0] = (self.actual_volume[0] * 32768.0) as i32;
+                    self.actual_volume_q15[1] = (self.actual_volume[1] * 32768.0) as i32;
+                }
 
                 let arp_note = if self.current.has_arpeggio() {
                     self.arpeggio.value()
                 } else {
                     0.0
-                };
+                } + self.pitch_bend;
 
                 instr.update_frequency(self.period, arp_note, self.vibrato.value(), self.semitone)
             }
@@ -221,6 +363,193 @@ impl<'a> Channel<'a> {
         }
     }
 
+    pub(crate) fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = semitones;
+    }
+
+    pub(crate) fn set_channel_volume(&mut self, volume: f32) {
+        self.channel_volume = volume;
+    }
+
+    /// Console (left, right) gains, reached by steps of `ramp` each sample
+    pub(crate) fn set_mix_gain(&mut self, gain: [f32; 2], ramp: f32) {
+        self.mix_target = gain;
+        self.mix_ramp = ramp;
+        #[cfg(feature = "fixed")]
+        {
+            let q23 = |gain: f32| (gain.min(255.0) * (1 << 23) as f32) as i32;
+            self.mix_target_q23 = [q23(gain[0]), q23(gain[1])];
+            self.mix_ramp_q23 = q23(ramp).max(1);
+        }
+    }
+
+    fn mix_gain_ramp(&mut self) {
+        for (gain, target) in self.mix_gain.iter_mut().zip(self.mix_target) {
+            if *gain < target {
+                *gain = (*gain + self.mix_ramp).min(target);
+            } else if *gain > target {
+                *gain = (*gain - self.mix_ramp).max(target);
+            }
+        }
+    }
+
+    /// Highest side gain (volume, envelopes and console), None if no sample is playing
+    pub(crate) fn loudness(&self) -> Option<f32> {
+        if !self
+            .instr
+            .as_ref()
+            .is_some_and(|i| i.is_enabled() && !i.is_ended())
+        {
+            return None;
+        }
+        let volume = self.actual_volume[0].max(self.actual_volume[1]);
+        let gain = self.mix_gain[0]
+            .max(self.mix_gain[1])
+            .max(self.mix_target[0].max(self.mix_target[1]));
+        Some(volume * gain)
+    }
+
+    pub(crate) fn set_panning(&mut self, panning: f32) {
+        self.panning = panning;
+    }
+
+    pub(crate) fn set_mix_laws(&mut self, pan_law: PanLaw, volume_curve: VolumeCurve) {
+        self.pan_law = pan_law;
+        self.volume_curve = volume_curve;
+    }
+
+    /// Use nearest neighbour instead of linear interpolation, faster but less accurate
+    pub(crate) fn set_nearest(&mut self, nearest: bool) {
+        self.nearest = nearest;
+        if let Some(instr) = &mut self.instr {
+            instr.nearest = nearest;
+            if let Some(s) = &mut instr.state_sample {
+                s.nearest = nearest;
+            }
+        }
+    }
+
+    pub(crate) fn set_default(&mut self, default: &ChannelDefault) {
+        self.channel_panning = default.panning.is_some();
+        if let Some(panning) = default.panning {
+            self.panning = panning;
+        }
+        self.channel_volume = default.volume;
+        self.disabled = !default.enabled;
+    }
+
+    /// True if a sample is playing with a non-zero volume
+    pub(crate) fn is_playing(&self) -> bool {
+        match &self.instr {
+            Some(i) => i.is_enabled() && self.final_volume > 0.0,
+            None => false,
+        }
+    }
+
+    /// True if the channel is not mixed: no sample playing or played to its end, zero volume or zero console gain
+    ///
+    /// The mixer calls `skip()` instead of `next()` on silent channels.
+    pub(crate) fn is_silent(&self) -> bool {
+        self.actual_volume == [0.0, 0.0]
+            || (self.mix_gain == [0.0, 0.0] && self.mix_target == [0.0, 0.0])
+            || !self
+                .instr
+                .as_ref()
+                .is_some_and(|i| i.is_enabled() && !i.is_ended())
+    }
+
+    pub(crate) fn get_final_volume(&self) -> f32 {
+        self.final_volume
+    }
+
+    /// Playback frequency in Hz, 0.0 if silent
+    pub(crate) fn get_frequency(&self) -> f32 {
+        self.instr
+            .as_ref()
+            .and_then(|i| i.state_sample.as_ref())
+            .map_or(0.0, |s| s.get_frequency())
+    }
+
+    /// Current instrument index
+    pub(crate) fn instrument_num(&self) -> Option<usize> {
+        self.instr.as_ref().map(|i| i.num)
+    }
+
+    /// Returns a snapshot of what the channel is playing
+    pub fn info(&self) -> ChannelInfo {
+        let sample = self.instr.as_ref().and_then(|i| i.state_sample.as_ref());
+        ChannelInfo {
+            note: self.played_note,
+            instrument: self.instrument_num(),
+            sample: self
+                .instr
+                .as_ref()
+                .and_then(|i| i.state_sample.as_ref().map(|_| i.sample_num)),
+            frequency: self.get_frequency(),
+            volume: if self.is_playing() {
+                self.final_volume
+            } else {
+                0.0
+            },
+            panning: self.final_panning,
+            position: sample.map_or(0.0, |s| s.get_playback_position()),
+            slot: self.current,
+            muted: self.is_muted(),
+        }
+    }
+
+    pub(crate) fn midi_release(&mut self, frame: u64, sink: &mut dyn MidiSink) {
+        self.midi.release(frame, sink);
+    }
+
+    /// Send MIDI events if current instrument has MIDI output enabled
+    ///
+    /// With `export_channel`, events are sent for all instruments on this MIDI channel, using instrument number as program.
+    pub(crate) fn midi_tick(
+        &mut self,
+        frame: u64,
+        sink: &mut dyn MidiSink,
+        export_channel: Option<u8>,
+    ) {
+        let triggered = self.triggered;
+
+        let instr = match &self.instr {
+            Some(i) if i.midi.on || export_channel.is_some() => i,
+            _ => {
+                self.midi.release(frame, sink);
+                return;
+            }
+        };
+
+        let (channel, program) = match export_channel {
+            Some(channel) => (channel, instr.num as u8),
+            None => (instr.midi.channel, instr.midi.program as u8),
+        };
+
+        if triggered {
+            self.midi
+                .trigger(frame, sink, channel, program, self.note, self.volume);
+        } else if !instr.sustained || self.volume == 0.0 {
+            // Key off or note cut
+            self.midi.release(frame, sink);
+            return;
+        }
+
+        let arp_note = if self.current.has_arpeggio() {
+            self.arpeggio.value()
+        } else {
+            0.0
+        } + self.pitch_bend;
+        let note = self.period_helper.period_to_note(self.period) + arp_note + self.vibrato.value();
+        let range = if instr.midi.bend != 0 {
+            instr.midi.bend as f32
+        } else {
+            2.0
+        };
+        self.midi
+            .update(frame, sink, note, range, self.final_volume);
+    }
+
     fn tick_effects(&mut self, current_tick: u16) {
         match self.current.effect_type {
             0 => {
@@ -670,34 +999,87 @@ impl<'a> Channel<'a> {
         }
     }
 
+    fn has_instrument(&self, index: usize) -> bool {
+        index < self.module.instrument.len()
+            || self.instrument_override.iter().any(|(i, _)| *i == index)
+    }
+
+    /// Default instrument `index`, None for other instrument types
+    fn get_instr_default(&self, index: usize) -> Option<InstrRef<'a>> {
+        match self.instrument_override.iter().find(|(i, _)| *i == index) {
+            Some((_, instr)) => Some(InstrRef::Shared(instr.clone())),
+            None => match &self.module.instrument.get(index)?.instr_type {
+                InstrumentType::Default(instr) => Some(InstrRef::Borrowed(instr)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Play `data` for instrument `index` from the next note
+    pub(crate) fn replace_instrument(&mut self, index: usize, data: Arc<InstrDefault>) {
+        match self
+            .instrument_override
+            .iter_mut()
+            .find(|(i, _)| *i == index)
+        {
+            Some(o) => o.1 = data,
+            None => self.instrument_override.push((index, data)),
+        }
+    }
+
+    /// Read frames of `sample` in `instrument` from `source`, from the next note
+    pub(crate) fn set_sample_source(
+        &mut self,
+        instrument: usize,
+        sample: usize,
+        source: SourceRef<'a>,
+    ) {
+        self.sample_source
+            .retain(|s| s.0 != instrument || s.1 != sample);
+        self.sample_source.push((instrument, sample, source));
+    }
+
+    /// Ask the sample source for the frames of the next `frames` output samples
+    pub(crate) fn prefetch(&self, frames: usize) {
+        if let Some(s) = self.instr.as_ref().and_then(|i| i.state_sample.as_ref()) {
+            s.prefetch(frames);
+        }
+    }
+
     /// Change instr and return true if it was the same
     fn tick0_change_instr(&mut self, sample_only: bool) -> bool {
         let instrnr = self.current.instrument as usize - 1;
 
-        if let InstrumentType::Default(id) = &self.module.instrument[instrnr].instr_type {
-            let was_same = self.instr.as_ref().map_or(false, |i| i.num == instrnr);
+        // TODO: other instrument types
+        let Some(id) = self.get_instr_default(instrnr) else {
+            return false;
+        };
+        let was_same = self.instr.as_ref().map_or(false, |i| i.num == instrnr);
 
-            // Only proceed if the instrument has samples
-            if !id.sample.is_empty() {
-                if sample_only {
-                    if let Some(i) = &mut self.instr {
-                        i.replace_instr(id);
-                    }
-                } else {
-                    self.instr = Some(StateInstrDefault::new(
-                        id,
-                        instrnr,
-                        self.period_helper.clone(),
-                        self.rate,
-                    ));
+        // Only proceed if the instrument has samples
+        if !id.sample.is_empty() {
+            if sample_only {
+                if let Some(i) = &mut self.instr {
+                    i.replace_instr(id);
+                }
+            } else {
+                let mut instr =
+                    StateInstrDefault::new(id, instrnr, self.period_helper.clone(), self.rate);
+                instr.nearest = self.nearest;
+                instr.sample_source = self
+                    .sample_source
+                    .iter()
+                    .filter(|s| s.0 == instrnr)
+                    .map(|s| (s.1, s.2.clone()))
+                    .collect();
+                if let Some(previous) = &mut self.instr {
+                    instr.set_block_buffers(previous.take_block_buffers());
                 }
+                self.instr = Some(instr);
             }
-
-            return was_same;
-        } else {
-            // TODO
-            return false;
         }
+
+        was_same
     }
 
     /// Return true if it was the same instrument
@@ -706,7 +1088,7 @@ impl<'a> Channel<'a> {
             return true; // No instrument to load
         }
 
-        if self.current.instrument as usize > self.module.instrument.len() {
+        if !self.has_instrument(self.current.instrument as usize - 1) {
             /* Invalid instrument, cut current note */
             self.cut_note();
             self.instr = None;
@@ -767,6 +1149,7 @@ impl<'a> Channel<'a> {
 
             // SetNote
             if instr.set_note(self.current.note) {
+                self.played_note = self.current.note;
                 if let Some(s) = &instr.state_sample {
                     self.note = self.current.note.value() as f32 - 1.0 + s.get_finetuned_note();
                 }
@@ -827,16 +1210,75 @@ impl<'a> Channel<'a> {
     }
 }
 
+impl<'a> Channel<'a> {
+    /// Add the next `out.len()` frames to `out`, same result as `next()` for each frame
+    pub(crate) fn mix_span(&mut self, out: &mut [(f32, f32)]) {
+        if self.mix_gain != self.mix_target {
+            for o in out.iter_mut() {
+                if let Some(fval) = self.next() {
+                    o.0 += fval.0;
+                    o.1 += fval.1;
+                }
+            }
+            return;
+        }
+        if let Some(s) = self.instr.as_mut().and_then(|i| i.state_sample.as_mut()) {
+            s.mix_span(out, self.actual_volume, self.mix_gain);
+        }
+    }
+
+    /// Same as `next()`, without computing the sample
+    pub(crate) fn skip(&mut self) {
+        if self.mix_gain != self.mix_target {
+            self.mix_gain_ramp();
+        }
+        #[cfg(feature = "fixed")]
+        self.mix_gain_ramp_q23();
+        if let Some(i) = &mut self.instr {
+            i.skip();
+        }
+    }
+}
+
+#[cfg(feature = "fixed")]
+impl<'a> Channel<'a> {
+    fn mix_gain_ramp_q23(&mut self) {
+        for (gain, target) in self.mix_gain_q23.iter_mut().zip(self.mix_target_q23) {
+            if *gain < target {
+                *gain = (*gain + self.mix_ramp_q23).min(target);
+            } else if *gain > target {
+                *gain = (*gain - self.mix_ramp_q23).max(target);
+            }
+        }
+    }
+
+    /// Same as `next()` with integer arithmetic, Q15 values
+    ///
+    /// Console gains go above 1.0, products are computed on 64 bits.
+    pub(crate) fn next_q15(&mut self) -> Option<(i32, i32)> {
+        self.mix_gain_ramp_q23();
+        let fval = self.instr.as_mut()?.next_q15()?;
+        let v = &self.actual_volume_q15;
+        let g = &self.mix_gain_q23;
+        let mix =
+            |f: i32, v: i32, g: i32| ((((f as i64 * v as i64) >> 15) * g as i64) >> 23) as i32;
+        Some((mix(fval.0, v[0], g[0]), mix(fval.1, v[1], g[1])))
+    }
+}
+
 impl<'a> Iterator for Channel<'a> {
     type Item = (f32, f32);
 
     // Was next_of_sample()
     fn next(&mut self) -> Option<Self::Item> {
+        if self.mix_gain != self.mix_target {
+            self.mix_gain_ramp();
+        }
         match &mut self.instr {
             Some(i) => match i.next() {
                 Some(fval) => Some((
-                    fval.0 * self.actual_volume[0],
-                    fval.1 * self.actual_volume[1],
+                    fval.0 * self.actual_volume[0] * self.mix_gain[0],
+                    fval.1 * self.actual_volume[1] * self.mix_gain[1],
                 )),
                 None => None,
             },
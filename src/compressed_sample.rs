@@ -0,0 +1,465 @@
+/// Sample frames kept compressed in memory and decoded by blocks while playing
+use crate::sample_source::SampleSource;
+use alloc::vec::Vec;
+
+/// Frames between two decoder checkpoints, decoded together while playing
+pub const BLOCK_LEN: usize = 1024;
+
+/// Compressed mono sample data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Signed 8 bits deltas, one byte per frame
+    Delta8,
+    /// ModPlug 4 bits ADPCM: two frames per byte (low nibble first), deltas read in this table
+    Adpcm4([i8; 16]),
+    /// Impulse Tracker compression, `it215` for double deltas (IT 2.15 files)
+    It214 { sixteen_bit: bool, it215: bool },
+}
+
+/// Decoder state, kept at the start of each block
+#[derive(Clone, Copy, Debug, Default)]
+struct DecoderState {
+    /// Next frame
+    frame: usize,
+    /// Bit position in data
+    bit: usize,
+    /// IT: bit width, frames left in current compressed block and byte offset of the next one
+    width: u8,
+    block_left: usize,
+    next_block: usize,
+    /// Delta and double delta accumulators
+    d1: i32,
+    d2: i32,
+}
+
+/// Compressed frames with decoder checkpoints every `BLOCK_LEN` frames, so that any frame
+/// (loop start, `9xx` sample offset...) is decoded from the nearest previous checkpoint
+#[derive(Clone, Debug)]
+pub struct CompressedSample {
+    compression: Compression,
+    data: Vec<u8>,
+    len: usize,
+    checkpoints: Vec<DecoderState>,
+}
+
+impl CompressedSample {
+    /// `len` frames compressed in `data`, decoded once to set checkpoints
+    ///
+    /// Missing data gives silent frames.
+    pub fn new(compression: Compression, data: Vec<u8>, len: usize) -> Self {
+        let mut sample = Self {
+            compression,
+            data,
+            len,
+            checkpoints: Vec::with_capacity(len.div_ceil(BLOCK_LEN)),
+        };
+        let mut state = DecoderState::default();
+        for frame in 0..len {
+            if frame % BLOCK_LEN == 0 {
+                sample.checkpoints.push(state);
+            }
+            sample.decode(&mut state);
+        }
+        sample
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Size of compressed data in bytes
+    pub fn compressed_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// `count` bits, least significant first, 0 after the end of data
+    fn read_bits(&self, state: &mut DecoderState, count: u8) -> u32 {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(state.bit / 8).copied().unwrap_or_default();
+            value |= (((byte >> (state.bit % 8)) & 1) as u32) << i;
+            state.bit += 1;
+        }
+        value
+    }
+
+    /// Next frame as a Q15 value
+    fn decode(&self, state: &mut DecoderState) -> i16 {
+        state.frame += 1;
+        match self.compression {
+            Compression::Delta8 => {
+                let delta = self.data.get(state.frame - 1).copied().unwrap_or_default();
+                state.d1 = (state.d1 as i8).wrapping_add(delta as i8) as i32;
+                (state.d1 as i16) << 8
+            }
+            Compression::Adpcm4(table) => {
+                let nibble = self.read_bits(state, 4) as usize;
+                state.d1 = (state.d1 as i8).wrapping_add(table[nibble]) as i32;
+                (state.d1 as i16) << 8
+            }
+            Compression::It214 { sixteen_bit, it215 } => {
+                let value = self.decode_it(state, sixteen_bit);
+                state.d1 = state.d1.wrapping_add(value);
+                state.d2 = state.d2.wrapping_add(state.d1);
+                let out = if it215 { state.d2 } else { state.d1 };
+                if sixteen_bit {
+                    out as i16
+                } else {
+                    (out as i8 as i16) << 8
+                }
+            }
+        }
+    }
+
+    /// Next IT delta, sign extended
+    fn decode_it(&self, state: &mut DecoderState, sixteen_bit: bool) -> i32 {
+        let (max_width, block_frames) = if sixteen_bit {
+            (17, 0x4000)
+        } else {
+            (9, 0x8000)
+        };
+        if state.block_left == 0 {
+            // Compressed blocks start with their size in bytes
+            let at = state.next_block;
+            let size = match self.data.get(at..at + 2) {
+                Some(b) => u16::from_le_bytes([b[0], b[1]]) as usize,
+                None => 0,
+            };
+            state.bit = (at + 2) * 8;
+            state.next_block = at + 2 + size;
+            state.block_left = block_frames;
+            state.width = max_width;
+            state.d1 = 0;
+            state.d2 = 0;
+        }
+        state.block_left -= 1;
+
+        loop {
+            let width = state.width;
+            let value = self.read_bits(state, width);
+            let new_width = if width < 7 {
+                if value == 1 << (width - 1) {
+                    // 4 bits in 16 bits samples, as in Schism Tracker and OpenMPT
+                    let bits = if sixteen_bit { 4 } else { 3 };
+                    Some(self.read_bits(state, bits) as u8 + 1)
+                } else {
+                    None
+                }
+            } else if width < max_width {
+                let border = ((if sixteen_bit { 0xFFFF } else { 0xFF }) >> (max_width - width))
+                    - if sixteen_bit { 8 } else { 4 };
+                if value > border && value <= border + if sixteen_bit { 16 } else { 8 } {
+                    Some((value - border) as u8)
+                } else {
+                    None
+                }
+            } else if value & (1 << (max_width - 1)) != 0 {
+                Some((value + 1) as u8)
+            } else {
+                None
+            };
+            match new_width {
+                Some(w) if width < max_width => {
+                    state.width = if w < width { w } else { w + 1 };
+                }
+                Some(w) => {
+                    if w == 0 || w > max_width {
+                        // Broken data
+                        return 0;
+                    }
+                    state.width = w;
+                }
+                None => {
+                    let bits = if sixteen_bit { 16 } else { 8 };
+                    let value_bits = width.min(bits);
+                    let shift = 32 - value_bits as u32;
+                    return ((value << shift) as i32) >> shift;
+                }
+            }
+        }
+    }
+
+    fn decode_into(&self, block: usize, count: usize, out: &mut Vec<(i16, i16)>) {
+        out.clear();
+        let Some(mut state) = self.checkpoints.get(block).copied() else {
+            return;
+        };
+        let end = (state.frame + count).min(self.len);
+        while state.frame < end {
+            let v = self.decode(&mut state);
+            out.push((v, v));
+        }
+    }
+}
+
+impl SampleSource for CompressedSample {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Decodes from the previous checkpoint, players read blocks with `decode_block()`
+    fn at(&self, index: usize) -> (f32, f32) {
+        let (l, r) = self.at_q15(index);
+        (l as f32 / 32768.0, r as f32 / 32768.0)
+    }
+
+    fn at_q15(&self, index: usize) -> (i32, i32) {
+        let Some(mut state) = self.checkpoints.get(index / BLOCK_LEN).copied() else {
+            return (0, 0);
+        };
+        let mut v = 0;
+        while state.frame <= index {
+            v = self.decode(&mut state);
+        }
+        (v as i32, v as i32)
+    }
+
+    fn block_len(&self) -> usize {
+        BLOCK_LEN
+    }
+
+    fn decode_block(&self, block: usize, out: &mut Vec<(i16, i16)>) {
+        self.decode_into(block, BLOCK_LEN, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_source::SourceRef;
+    use crate::state_sample::StateSample;
+    use crate::test_module::wave;
+    use alloc::vec;
+    use xmrs::prelude::*;
+
+    /// Bits written least significant first, as read by the decoder
+    #[derive(Default)]
+    struct Bits {
+        data: Vec<u8>,
+        count: usize,
+    }
+
+    impl Bits {
+        fn write(&mut self, value: u32, count: u8) -> &mut Self {
+            for i in 0..count {
+                if self.count.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                *self.data.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (self.count % 8);
+                self.count += 1;
+            }
+            self
+        }
+
+        /// IT compressed block: size in bytes, then bits
+        fn it_block(&self, data: &mut Vec<u8>) {
+            data.extend_from_slice(&(self.data.len() as u16).to_le_bytes());
+            data.extend_from_slice(&self.data);
+        }
+    }
+
+    /// 8 bits frames of `wave()`
+    fn wave8(len: usize) -> Vec<i8> {
+        match wave(len).data {
+            SampleDataType::Mono16(v) => v.iter().map(|&s| (s >> 8) as i8).collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Frames decoded one by one from checkpoints, checked against the blocks read while playing
+    fn frames(sample: &CompressedSample) -> Vec<i16> {
+        let frames: Vec<i16> = (0..sample.len())
+            .map(|i| sample.at_q15(i).0 as i16)
+            .collect();
+        let mut blocks = vec![];
+        let mut block = vec![];
+        for b in 0..sample.len().div_ceil(BLOCK_LEN) {
+            sample.decode_block(b, &mut block);
+            blocks.extend(block.iter().map(|f| f.0));
+        }
+        assert_eq!(blocks, frames);
+        frames
+    }
+
+    fn sum(deltas: &[i32]) -> Vec<i32> {
+        deltas
+            .iter()
+            .scan(0i32, |s, &d| {
+                *s = s.wrapping_add(d);
+                Some(*s)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta8() {
+        let values = wave8(3000);
+        let deltas = (0..values.len())
+            .map(|i| values[i].wrapping_sub(if i > 0 { values[i - 1] } else { 0 }) as u8)
+            .collect();
+        let sample = CompressedSample::new(Compression::Delta8, deltas, values.len());
+        let expected: Vec<i16> = values.iter().map(|&v| (v as i16) << 8).collect();
+        assert_eq!(frames(&sample), expected);
+    }
+
+    #[test]
+    fn adpcm4() {
+        let table = [
+            0, 1, 2, 4, 8, 16, 32, 64, -1, -2, -4, -8, -16, -32, -64, -128,
+        ];
+        let nibbles: Vec<u8> = (0..2101).map(|i| (i * 5 % 16) as u8).collect();
+        // Low nibble first, the last byte is half used
+        let data = nibbles
+            .chunks(2)
+            .map(|n| n[0] | n.get(1).map_or(0, |h| h << 4))
+            .collect();
+        let sample = CompressedSample::new(Compression::Adpcm4(table), data, nibbles.len());
+        let deltas: Vec<i32> = nibbles.iter().map(|&n| table[n as usize] as i32).collect();
+        let expected: Vec<i16> = sum(&deltas)
+            .iter()
+            .map(|&v| (v as i8 as i16) << 8)
+            .collect();
+        assert_eq!(frames(&sample), expected);
+    }
+
+    #[test]
+    fn it214_8_bits() {
+        let mut bits = Bits::default();
+        bits.write(5, 9)
+            .write(-3i32 as u32 & 0xFF, 9)
+            // 9 to 3 bits
+            .write(0x100 | 2, 9)
+            .write(1, 3)
+            .write(-2i32 as u32 & 0x7, 3)
+            .write(3, 3)
+            // 3 to 8 bits: 7, the current width is skipped
+            .write(4, 3)
+            .write(6, 3)
+            .write(100, 8)
+            .write(-100i32 as u32 & 0xFF, 8)
+            // 8 to 5 bits, around the border value 0x7B
+            .write(0x7B + 5, 8)
+            .write(-7i32 as u32 & 0x1F, 5)
+            // 5 to 9 bits
+            .write(16, 5)
+            .write(7, 3)
+            .write(0x7F, 9);
+        let mut data = vec![];
+        bits.it_block(&mut data);
+        let deltas = [5, -3, 1, -2, 3, 100, -100, -7, 127];
+
+        for it215 in [false, true] {
+            let compression = Compression::It214 {
+                sixteen_bit: false,
+                it215,
+            };
+            let sample = CompressedSample::new(compression, data.clone(), deltas.len());
+            let mut expected = sum(&deltas);
+            if it215 {
+                expected = sum(&expected);
+            }
+            let expected: Vec<i16> = expected.iter().map(|&v| (v as i8 as i16) << 8).collect();
+            assert_eq!(frames(&sample), expected);
+        }
+    }
+
+    #[test]
+    fn it214_16_bits() {
+        let mut bits = Bits::default();
+        bits.write(-1000i32 as u32 & 0xFFFF, 17)
+            // 17 to 4 bits
+            .write(0x10000 | 3, 17)
+            .write(-5i32 as u32 & 0xF, 4)
+            // 4 to 9 bits, the new width is read on 4 bits
+            .write(8, 4)
+            .write(7, 4)
+            .write(200, 9)
+            .write(-200i32 as u32 & 0x1FF, 9)
+            // 9 to 16 bits, around the border value 0xF7
+            .write(0xF7 + 15, 9)
+            .write(30000, 16)
+            .write(-30000i32 as u32 & 0xFFFF, 16)
+            // 16 to 5 bits, around the border value 0x7FF7
+            .write(0x7FF7 + 5, 16)
+            .write(9, 5)
+            // 5 to 14 bits, out of reach of a 3 bits width
+            .write(16, 5)
+            .write(12, 4)
+            .write(-6000i32 as u32 & 0x3FFF, 14);
+        let mut data = vec![];
+        bits.it_block(&mut data);
+        let deltas = [-1000, -5, 200, -200, 30000, -30000, 9, -6000];
+
+        for it215 in [false, true] {
+            let compression = Compression::It214 {
+                sixteen_bit: true,
+                it215,
+            };
+            let sample = CompressedSample::new(compression, data.clone(), deltas.len());
+            let mut expected = sum(&deltas);
+            if it215 {
+                expected = sum(&expected);
+            }
+            let expected: Vec<i16> = expected.iter().map(|&v| v as i16).collect();
+            assert_eq!(frames(&sample), expected);
+        }
+    }
+
+    #[test]
+    fn it214_blocks_restart_deltas() {
+        // 0x8000 frames by compressed block in 8 bits
+        let mut data = vec![];
+        for (delta, count) in [(1, 0x8000), (2, 10)] {
+            let mut bits = Bits::default();
+            for _ in 0..count {
+                bits.write(delta, 9);
+            }
+            bits.it_block(&mut data);
+        }
+        let compression = Compression::It214 {
+            sixteen_bit: false,
+            it215: false,
+        };
+        let sample = CompressedSample::new(compression, data, 0x8000 + 10);
+        let expected: Vec<i16> = (1..=0x8000)
+            .map(|i| i as i8)
+            .chain((1..=10).map(|i| 2 * i))
+            .map(|v| (v as i16) << 8)
+            .collect();
+        assert_eq!(frames(&sample), expected);
+
+        // Missing data is silent
+        let sample = CompressedSample::new(compression, vec![], 100);
+        assert_eq!(frames(&sample), vec![0; 100]);
+    }
+
+    #[test]
+    fn plays_loops_and_sample_offsets() {
+        let values = wave8(5000);
+        let deltas = (0..values.len())
+            .map(|i| values[i].wrapping_sub(if i > 0 { values[i - 1] } else { 0 }) as u8)
+            .collect();
+        let compressed = CompressedSample::new(Compression::Delta8, deltas, values.len());
+        let mut sample = wave(1);
+        sample.data = SampleDataType::Mono8(values);
+        sample.loop_start = 1500;
+        sample.loop_length = 2100;
+
+        for flags in [LoopType::No, LoopType::Forward, LoopType::PingPong] {
+            sample.flags = flags;
+            // 9xx offsets, around checkpoints
+            for offset in [0, 1023, 1024, 2500, 4999] {
+                let mut memory = StateSample::new(&sample, SourceRef::Borrowed(&sample), 44100.0);
+                let mut state =
+                    StateSample::new(&sample, SourceRef::Borrowed(&compressed), 44100.0);
+                for s in [&mut memory, &mut state] {
+                    s.set_step(44100.0 * 1.37);
+                    s.set_position(offset);
+                }
+                for _ in 0..8000 {
+                    assert_eq!(state.next(), memory.next());
+                }
+            }
+        }
+    }
+}
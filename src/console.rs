@@ -0,0 +1,163 @@
+/// Mixing console: per-channel gain, pan, mute, solo and groups
+use alloc::{vec, vec::Vec};
+
+/// Settings of one song channel
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelStrip {
+    /// Gain multiplied with channel volume (default 1.0)
+    pub gain: f32,
+    /// Balance: -1.0 (left) to 1.0 (right), 0.0 for centered
+    pub pan: f32,
+    pub mute: bool,
+    pub solo: bool,
+    /// Mute/solo group
+    pub group: Option<usize>,
+}
+
+impl Default for ChannelStrip {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            pan: 0.0,
+            mute: false,
+            solo: false,
+            group: None,
+        }
+    }
+}
+
+/// Mute and solo shared by several channels
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Group {
+    pub mute: bool,
+    pub solo: bool,
+}
+
+/// Changes are applied at each tick, ramped over `ramp_time` seconds to avoid clicks
+///
+/// When a channel or a group is soloed, all other channels are silent.
+#[derive(Clone, Debug)]
+pub struct Console {
+    pub strip: Vec<ChannelStrip>,
+    pub group: Vec<Group>,
+    /// Instruments (index in `module.instrument`) muted on all channels
+    pub muted_instruments: Vec<usize>,
+    /// Time to go from 0.0 to 1.0 gain, in seconds (default 0.01)
+    pub ramp_time: f32,
+}
+
+impl Console {
+    pub fn new(num_channels: usize) -> Self {
+        Self {
+            strip: vec![ChannelStrip::default(); num_channels],
+            group: vec![],
+            muted_instruments: vec![],
+            ramp_time: 0.01,
+        }
+    }
+
+    pub fn set_gain(&mut self, channel: usize, gain: f32) {
+        if let Some(s) = self.strip.get_mut(channel) {
+            s.gain = gain.max(0.0);
+        }
+    }
+
+    pub fn set_pan(&mut self, channel: usize, pan: f32) {
+        if let Some(s) = self.strip.get_mut(channel) {
+            s.pan = pan.clamp(-1.0, 1.0);
+        }
+    }
+
+    pub fn set_mute(&mut self, channel: usize, mute: bool) {
+        if let Some(s) = self.strip.get_mut(channel) {
+            s.mute = mute;
+        }
+    }
+
+    pub fn set_solo(&mut self, channel: usize, solo: bool) {
+        if let Some(s) = self.strip.get_mut(channel) {
+            s.solo = solo;
+        }
+    }
+
+    /// Put a channel in a group, None to remove it
+    pub fn set_group(&mut self, channel: usize, group: Option<usize>) {
+        if let Some(g) = group {
+            if g >= self.group.len() {
+                self.group.resize(g + 1, Group::default());
+            }
+        }
+        if let Some(s) = self.strip.get_mut(channel) {
+            s.group = group;
+        }
+    }
+
+    pub fn set_group_mute(&mut self, group: usize, mute: bool) {
+        if let Some(g) = self.group.get_mut(group) {
+            g.mute = mute;
+        }
+    }
+
+    pub fn set_group_solo(&mut self, group: usize, solo: bool) {
+        if let Some(g) = self.group.get_mut(group) {
+            g.solo = solo;
+        }
+    }
+
+    pub fn mute_instrument(&mut self, instrument: usize, mute: bool) {
+        self.muted_instruments.retain(|&i| i != instrument);
+        if mute {
+            self.muted_instruments.push(instrument);
+        }
+    }
+
+    /// Clear all mutes and solos
+    pub fn clear(&mut self) {
+        for s in &mut self.strip {
+            s.mute = false;
+            s.solo = false;
+        }
+        for g in &mut self.group {
+            *g = Group::default();
+        }
+        self.muted_instruments.clear();
+    }
+
+    fn get_group(&self, strip: &ChannelStrip) -> Group {
+        strip
+            .group
+            .and_then(|g| self.group.get(g))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn any_solo(&self) -> bool {
+        self.strip.iter().any(|s| s.solo || self.get_group(s).solo)
+    }
+
+    /// Channel muted, by its strip, its group, `instrument` or another solo
+    pub(crate) fn is_muted(&self, channel: usize, instrument: Option<usize>) -> bool {
+        let Some(strip) = self.strip.get(channel) else {
+            return false;
+        };
+        let group = self.get_group(strip);
+        strip.mute
+            || group.mute
+            || instrument.is_some_and(|i| self.muted_instruments.contains(&i))
+            || (self.any_solo() && !strip.solo && !group.solo)
+    }
+
+    /// Target (left, right) gains of a channel playing `instrument`
+    pub(crate) fn gains(&self, channel: usize, instrument: Option<usize>) -> [f32; 2] {
+        let Some(strip) = self.strip.get(channel) else {
+            return [1.0, 1.0];
+        };
+        if self.is_muted(channel, instrument) {
+            return [0.0, 0.0];
+        }
+        [
+            strip.gain * (1.0 - strip.pan).min(1.0),
+            strip.gain * (1.0 + strip.pan).min(1.0),
+        ]
+    }
+}
@@ -0,0 +1,201 @@
+/// Ping-pong buffer for DMA or interrupt driven audio output
+use crate::xmrsplayer::XmrsPlayer;
+#[cfg(feature = "micromath")]
+#[allow(unused_imports)]
+use micromath::F32Ext;
+#[cfg(feature = "libm")]
+#[allow(unused_imports)]
+use num_traits::float::Float;
+
+/// Output sample format of a `DmaBuffer`
+pub trait OutputFormat {
+    /// Buffer element, as read by the DMA
+    type Word: Copy + Default;
+    /// Words for each (left, right) frame
+    const WORDS: usize;
+    /// Convert a frame into `WORDS` words
+    fn write(frame: (f32, f32), out: &mut [Self::Word]);
+}
+
+/// Signed 16 bits, left and right interleaved
+pub struct I16Stereo;
+
+impl OutputFormat for I16Stereo {
+    type Word = i16;
+    const WORDS: usize = 2;
+    fn write(frame: (f32, f32), out: &mut [i16]) {
+        out[0] = (frame.0.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+        out[1] = (frame.1.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+    }
+}
+
+/// Unsigned 8 bits mono, 128 for silence
+pub struct U8Mono;
+
+impl OutputFormat for U8Mono {
+    type Word = u8;
+    const WORDS: usize = 1;
+    fn write(frame: (f32, f32), out: &mut [u8]) {
+        let mono = ((frame.0 + frame.1) * 0.5).clamp(-1.0, 1.0);
+        out[0] = (mono * 127.0 + 128.0).round() as u8;
+    }
+}
+
+/// 12 bits DAC values right aligned, 2048 for silence, left and right interleaved
+pub struct Dac12Stereo;
+
+impl OutputFormat for Dac12Stereo {
+    type Word = u16;
+    const WORDS: usize = 2;
+    fn write(frame: (f32, f32), out: &mut [u16]) {
+        out[0] = (frame.0.clamp(-1.0, 1.0) * 2047.0 + 2048.0).round() as u16;
+        out[1] = (frame.1.clamp(-1.0, 1.0) * 2047.0 + 2048.0).round() as u16;
+    }
+}
+
+/// Two halves of a circular DMA buffer: one is played while the other one is rendered
+///
+/// The DMA half transfer and transfer complete interrupts call `half_played()`, then `render()`
+/// fills the halves already played, from the interrupt or from the main loop.
+/// Both halves are rendered by the first `render()`, before starting the DMA.
+/// If the main loop and an interrupt share it, access must be protected (critical section).
+pub struct DmaBuffer<'b, F: OutputFormat> {
+    buffer: &'b mut [F::Word],
+    /// Halves to render
+    pending: [bool; 2],
+    /// Half read by the DMA, None before the first interrupt
+    playing: Option<usize>,
+    underruns: usize,
+    finished: bool,
+}
+
+impl<'b, F: OutputFormat> DmaBuffer<'b, F> {
+    /// `buffer` is the whole DMA buffer, its length a multiple of `2 * F::WORDS`
+    pub fn new(buffer: &'b mut [F::Word]) -> Self {
+        Self {
+            buffer,
+            pending: [true, true],
+            playing: None,
+            underruns: 0,
+            finished: false,
+        }
+    }
+
+    /// Whole buffer, to give to the DMA
+    pub fn buffer(&self) -> &[F::Word] {
+        self.buffer
+    }
+
+    /// Frames in each half
+    pub fn half_len(&self) -> usize {
+        self.buffer.len() / (2 * F::WORDS)
+    }
+
+    /// `half` (0 or 1) was played, the DMA goes on with the other half
+    ///
+    /// An underrun is counted if the other half is not rendered yet: old data is played again.
+    pub fn half_played(&mut self, half: usize) {
+        let half = half & 1;
+        if self.pending[half ^ 1] {
+            self.underruns += 1;
+        }
+        self.pending[half] = true;
+        self.playing = Some(half ^ 1);
+    }
+
+    /// Render the halves already played, silence after the end of the song
+    ///
+    /// The half read by the DMA is never rendered: after an underrun, it is played again
+    /// and rendered once played, so that the song goes on in order.
+    /// Returns false once the song is finished.
+    pub fn render(&mut self, player: &mut XmrsPlayer) -> bool {
+        let words = self.half_len() * F::WORDS;
+        let mut frames = [(0.0, 0.0); 64];
+        for half in 0..2 {
+            if !self.pending[half] || self.playing == Some(half) {
+                continue;
+            }
+            let buffer = &mut self.buffer[half * words..(half + 1) * words];
+            for out in buffer.chunks_mut(frames.len() * F::WORDS) {
+                let frames = &mut frames[..out.len() / F::WORDS];
+                let written = if self.finished {
+                    0
+                } else {
+                    player.render(frames)
+                };
+                if written < frames.len() {
+                    self.finished = true;
+                    frames[written..].fill((0.0, 0.0));
+                }
+                for (frame, out) in frames.iter().zip(out.chunks_exact_mut(F::WORDS)) {
+                    F::write(*frame, out);
+                }
+            }
+            self.pending[half] = false;
+        }
+        !self.finished
+    }
+
+    /// Number of halves played before being rendered
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::song;
+    use alloc::{vec, vec::Vec};
+
+    #[test]
+    fn interrupts_play_the_song_in_order() {
+        let module = song(2, 1);
+        let mut player = XmrsPlayer::new(&module, 2000.0, false);
+        player.set_max_loop_count(1);
+        let mut expected = vec![];
+        while let Some(frame) = player.sample(true) {
+            let mut words = [0; 2];
+            I16Stereo::write(frame, &mut words);
+            expected.extend_from_slice(&words);
+        }
+
+        let mut player = XmrsPlayer::new(&module, 2000.0, false);
+        player.set_max_loop_count(1);
+        let mut buffer = [0i16; 400];
+        let mut dma = DmaBuffer::<I16Stereo>::new(&mut buffer);
+        let words = dma.half_len() * 2;
+        assert!(dma.render(&mut player));
+
+        // Halves really played, without the ones played again by underruns
+        let mut played: Vec<i16> = vec![];
+        let mut stale = false;
+        for i in 0..60 {
+            let half = i % 2;
+            if !stale {
+                played.extend_from_slice(&dma.buffer()[half * words..(half + 1) * words]);
+            }
+            // Half transfer or transfer complete interrupt
+            let underruns = dma.underruns();
+            dma.half_played(half);
+            stale = dma.underruns() > underruns;
+            // The main loop misses some interrupts, while the DMA reads either half
+            if i != 10 && i != 21 {
+                dma.render(&mut player);
+            }
+        }
+        assert_eq!(dma.underruns(), 2);
+        assert!(dma.is_finished());
+        assert!(!dma.render(&mut player));
+
+        // The song, then silence
+        assert!(played.len() > expected.len() + words);
+        expected.resize(played.len(), 0);
+        let first = played.iter().zip(&expected).position(|(a, b)| a != b);
+        assert_eq!(first, None);
+    }
+}
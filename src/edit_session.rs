@@ -0,0 +1,328 @@
+/// Edit patterns, order list and instruments while the song is playing
+use crate::pattern_source::PatternSource;
+use crate::sample_source::SampleSource;
+use alloc::{sync::Arc, vec, vec::Vec};
+use xmrs::prelude::*;
+
+/// Edit command, queued with `XmrsPlayer::edit()` and applied between two ticks
+#[derive(Clone)]
+pub enum EditCommand {
+    /// Replace a slot
+    SetSlot {
+        pattern: usize,
+        row: usize,
+        channel: usize,
+        slot: PatternSlot,
+    },
+    /// Insert an empty row before `row`, the pattern grows
+    InsertRow { pattern: usize, row: usize },
+    /// Delete a row, the pattern shrinks (a pattern keeps at least one row)
+    DeleteRow { pattern: usize, row: usize },
+    /// Change the pattern played at a position
+    SetOrder { position: usize, pattern: usize },
+    /// Insert a position in the order list
+    InsertOrder { position: usize, pattern: usize },
+    /// Delete a position (the order list keeps at least one position)
+    DeleteOrder { position: usize },
+    /// Play another instrument, for example with new sample data, from the next note
+    ///
+    /// A note already playing ends with the previous instrument.
+    /// `data` can be built while playing, it is shared with the player.
+    ReplaceInstrument {
+        instrument: usize,
+        data: Arc<InstrDefault>,
+    },
+    /// Play sample frames created while playing, for example a recording, from the next note
+    ///
+    /// Loop points, volume and panning still come from the module sample, like `XmrsPlayer::set_sample_source()`.
+    /// `data` can be a `Sample`.
+    ReplaceSample {
+        instrument: usize,
+        sample: usize,
+        data: Arc<dyn SampleSource + Send>,
+    },
+}
+
+/// Editable copy of module patterns and order list
+///
+/// Give it to `XmrsPlayer::set_pattern_source()`, then edits are sent with `XmrsPlayer::edit()`.
+/// Instruments and samples are not copied: the module ones are played until replaced
+/// with `EditCommand::ReplaceInstrument` or `EditCommand::ReplaceSample`, which own their data.
+pub struct EditSession {
+    pub pattern_order: Vec<usize>,
+    pub pattern: Vec<Pattern>,
+    pub restart_position: usize,
+    num_channels: usize,
+}
+
+impl EditSession {
+    pub fn new(module: &Module) -> Self {
+        Self {
+            pattern_order: module.pattern_order.clone(),
+            pattern: module.pattern.clone(),
+            restart_position: module.restart_position,
+            num_channels: module.get_num_channels(),
+        }
+    }
+
+    /// Apply a pattern or order list command, returns false if nothing was changed
+    pub fn apply(&mut self, command: &EditCommand) -> bool {
+        match *command {
+            EditCommand::SetSlot {
+                pattern,
+                row,
+                channel,
+                slot,
+            } => match self
+                .pattern
+                .get_mut(pattern)
+                .and_then(|p| p.get_mut(row))
+                .and_then(|r| r.get_mut(channel))
+            {
+                Some(s) => {
+                    *s = slot;
+                    true
+                }
+                None => false,
+            },
+            EditCommand::InsertRow { pattern, row } => {
+                let num_channels = self.num_channels;
+                match self.pattern.get_mut(pattern) {
+                    Some(p) if row <= p.len() && p.len() < MAX_NUM_ROWS => {
+                        p.insert(row, vec![PatternSlot::default(); num_channels]);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            EditCommand::DeleteRow { pattern, row } => match self.pattern.get_mut(pattern) {
+                Some(p) if row < p.len() && p.len() > 1 => {
+                    p.remove(row);
+                    true
+                }
+                _ => false,
+            },
+            EditCommand::SetOrder { position, pattern } => {
+                match self.pattern_order.get_mut(position) {
+                    Some(p) if pattern < self.pattern.len() => {
+                        *p = pattern;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            EditCommand::InsertOrder { position, pattern } => {
+                if position <= self.pattern_order.len() && pattern < self.pattern.len() {
+                    self.pattern_order.insert(position, pattern);
+                    if position <= self.restart_position && self.pattern_order.len() > 1 {
+                        self.restart_position += 1;
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            EditCommand::DeleteOrder { position } => {
+                if position < self.pattern_order.len() && self.pattern_order.len() > 1 {
+                    self.pattern_order.remove(position);
+                    if position < self.restart_position {
+                        self.restart_position -= 1;
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            EditCommand::ReplaceInstrument { .. } | EditCommand::ReplaceSample { .. } => false,
+        }
+    }
+
+    fn pattern(&self, position: usize) -> Option<&Pattern> {
+        self.pattern_order
+            .get(position)
+            .and_then(|&p| self.pattern.get(p))
+    }
+}
+
+impl PatternSource for EditSession {
+    fn song_length(&self) -> usize {
+        self.pattern_order.len()
+    }
+
+    fn restart_position(&self) -> usize {
+        self.restart_position
+    }
+
+    fn pattern_at(&self, position: usize) -> Option<usize> {
+        self.pattern_order.get(position).copied()
+    }
+
+    fn num_rows(&mut self, position: usize) -> usize {
+        self.pattern(position).map_or(0, |p| p.len())
+    }
+
+    fn get_row(&mut self, position: usize, row: usize, slots: &mut [PatternSlot]) {
+        let row = self.pattern(position).and_then(|p| p.get(row));
+        for (i, slot) in slots.iter_mut().enumerate() {
+            *slot = row.and_then(|r| r.get(i)).copied().unwrap_or_default();
+        }
+    }
+
+    fn edit(&mut self, command: &EditCommand) -> bool {
+        self.apply(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::*;
+    use crate::xmrsplayer::XmrsPlayer;
+    use alloc::boxed::Box;
+
+    /// Play `rows` rows at default speed, returns the last left sample
+    fn play_rows(player: &mut XmrsPlayer, rows: usize) -> f32 {
+        let mut left = 0.0;
+        for _ in 0..rows * 6 * 960 {
+            left = player.sample(true).unwrap_or((0.0, 0.0)).0;
+        }
+        left
+    }
+
+    fn editable(module: &Module) -> XmrsPlayer<'_> {
+        let mut player = XmrsPlayer::new(module, 48000.0, false);
+        player.set_pattern_source(Box::new(EditSession::new(module)));
+        player
+    }
+
+    #[test]
+    fn slot_set_while_playing() {
+        let module = module(vec![dc(16384)], vec![pattern(4, 1)], vec![0]);
+        let mut player = editable(&module);
+        assert_eq!(play_rows(&mut player, 1), 0.0);
+        player.edit(EditCommand::SetSlot {
+            pattern: 0,
+            row: 2,
+            channel: 0,
+            slot: note(Note::C4, 1),
+        });
+        assert_eq!(play_rows(&mut player, 1), 0.0);
+        assert!(play_rows(&mut player, 1) > 0.01);
+        // The module itself is not edited
+        assert_eq!(module.pattern[0][2][0], PatternSlot::default());
+    }
+
+    #[test]
+    fn pattern_shrinks_under_current_row() {
+        let mut next = pattern(4, 1);
+        next[0][0] = note(Note::C4, 1);
+        let module = module(vec![dc(16384)], vec![pattern(8, 1), next], vec![0, 1]);
+        let mut player = editable(&module);
+        play_rows(&mut player, 6);
+        assert_eq!(player.played_row, (0, 5));
+        for _ in 0..3 {
+            player.edit(EditCommand::DeleteRow { pattern: 0, row: 0 });
+        }
+        // Next row was 6, the pattern has now 5 rows
+        assert!(play_rows(&mut player, 1) > 0.01);
+        assert_eq!(player.played_row, (1, 0));
+    }
+
+    #[test]
+    fn order_inserted_while_playing() {
+        let mut next = pattern(2, 1);
+        next[0][0] = note(Note::C4, 1);
+        let module = module(vec![dc(16384)], vec![pattern(2, 1), next], vec![0]);
+        let mut player = editable(&module);
+        play_rows(&mut player, 1);
+        player.edit(EditCommand::InsertOrder {
+            position: 1,
+            pattern: 1,
+        });
+        assert_eq!(play_rows(&mut player, 1), 0.0);
+        assert!(play_rows(&mut player, 1) > 0.01);
+        assert_eq!(player.played_row, (1, 0));
+    }
+
+    #[test]
+    fn order_commands() {
+        let mut module = module(vec![], vec![pattern(1, 1); 3], vec![0, 1, 2]);
+        module.restart_position = 1;
+        let mut session = EditSession::new(&module);
+
+        let set = |position, pattern| EditCommand::SetOrder { position, pattern };
+        assert!(session.apply(&set(0, 2)));
+        assert!(!session.apply(&set(0, 3)));
+        assert!(!session.apply(&set(3, 0)));
+        assert_eq!(session.pattern_order, vec![2, 1, 2]);
+
+        let insert = |position, pattern| EditCommand::InsertOrder { position, pattern };
+        assert!(session.apply(&insert(0, 0)));
+        assert!(session.apply(&insert(4, 1)));
+        assert!(!session.apply(&insert(6, 0)));
+        assert!(!session.apply(&insert(0, 3)));
+        assert_eq!(session.pattern_order, vec![0, 2, 1, 2, 1]);
+        assert_eq!(session.restart_position, 2);
+
+        let delete = |position| EditCommand::DeleteOrder { position };
+        assert!(!session.apply(&delete(5)));
+        assert!(session.apply(&delete(0)));
+        assert!(session.apply(&delete(0)));
+        assert_eq!(session.pattern_order, vec![1, 2, 1]);
+        assert_eq!(session.restart_position, 0);
+        assert!(session.apply(&delete(2)));
+        assert!(session.apply(&delete(1)));
+        // The order list keeps one position
+        assert!(!session.apply(&delete(0)));
+        assert_eq!(session.pattern_order, vec![1]);
+    }
+
+    #[test]
+    fn row_commands() {
+        let module = module(vec![], vec![pattern(2, 3)], vec![0]);
+        let mut session = EditSession::new(&module);
+        assert!(session.apply(&EditCommand::InsertRow { pattern: 0, row: 2 }));
+        assert!(!session.apply(&EditCommand::InsertRow { pattern: 0, row: 4 }));
+        assert!(!session.apply(&EditCommand::InsertRow { pattern: 1, row: 0 }));
+        assert_eq!(session.pattern[0], pattern(3, 3));
+
+        let delete = EditCommand::DeleteRow { pattern: 0, row: 0 };
+        assert!(session.apply(&delete));
+        assert!(session.apply(&delete));
+        // A pattern keeps one row
+        assert!(!session.apply(&delete));
+        assert_eq!(session.pattern[0].len(), 1);
+
+        let slot = |row, channel| EditCommand::SetSlot {
+            pattern: 0,
+            row,
+            channel,
+            slot: note(Note::C4, 1),
+        };
+        assert!(session.apply(&slot(0, 2)));
+        assert!(!session.apply(&slot(0, 3)));
+        assert!(!session.apply(&slot(1, 0)));
+        assert_eq!(session.pattern[0][0][2], note(Note::C4, 1));
+    }
+
+    #[test]
+    fn instrument_replaced_while_playing() {
+        let mut pattern = pattern(4, 1);
+        pattern[0][0] = note(Note::C4, 1);
+        pattern[2][0] = note(Note::C4, 1);
+        let module = module(vec![dc(16384)], vec![pattern], vec![0]);
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        assert!(play_rows(&mut player, 1) > 0.01);
+
+        // Built while playing, the player keeps it alive
+        let mut instr = InstrDefault::default();
+        instr.sample.push(dc(-16384));
+        player.edit(EditCommand::ReplaceInstrument {
+            instrument: 0,
+            data: Arc::new(instr),
+        });
+        // The playing note ends with the previous instrument
+        assert!(play_rows(&mut player, 1) > 0.01);
+        assert!(play_rows(&mut player, 1) < -0.01);
+    }
+}
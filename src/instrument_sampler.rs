@@ -0,0 +1,312 @@
+/// Play module instruments outside of the song
+use crate::channel::Channel;
+use crate::helper::*;
+use crate::historical_helper::HistoricalHelper;
+use alloc::{vec, vec::Vec};
+use xmrs::prelude::*;
+
+#[derive(Clone)]
+struct Voice<'a> {
+    channel: Channel<'a>,
+    /// Voices can be grouped, for example by MIDI channel
+    part: u8,
+    instrument: usize,
+    note: Note,
+    /// Voice allocation order, used to steal the oldest voice
+    age: u64,
+    released: bool,
+}
+
+/// A polyphonic sampler using `Module` instruments
+///
+/// Each voice is a `Channel`, so envelopes, fadeout and auto-vibrato behave as in song playback.
+pub struct InstrumentSampler<'a> {
+    module: &'a Module,
+    sample_rate: f32,
+    hhelper: Option<HistoricalHelper>,
+
+    tempo: u16,
+    bpm: u16,
+    current_tick: u16,
+    /// sample rate / (BPM * 0.4)
+    remaining_samples_in_tick: f32,
+
+    voices: Vec<Voice<'a>>,
+    /// Last frame of each voice, reused from frame to frame
+    frames: Vec<(f32, f32)>,
+    next_age: u64,
+    pitch_bend: f32,
+
+    /// Global volume: 0.0 to 1.0
+    pub global_volume: f32,
+    /// Global amplification (default 1.0)
+    pub amplification: f32,
+}
+
+impl<'a> InstrumentSampler<'a> {
+    pub fn new(module: &'a Module, sample_rate: f32, historical: bool, max_voices: usize) -> Self {
+        let hhelper = if historical {
+            Some(HistoricalHelper::new(module.default_tempo))
+        } else {
+            None
+        };
+        let voice = Voice {
+            channel: Channel::new(module, sample_rate, hhelper),
+            part: 0,
+            instrument: 0,
+            note: Note::None,
+            age: 0,
+            released: true,
+        };
+        Self {
+            module,
+            sample_rate,
+            hhelper,
+            tempo: module.default_tempo,
+            bpm: module.default_bpm,
+            current_tick: 0,
+            remaining_samples_in_tick: 0.0,
+            voices: vec![voice; max_voices],
+            frames: vec![(0.0, 0.0); max_voices],
+            next_age: 0,
+            pitch_bend: 0.0,
+            global_volume: 1.0,
+            amplification: 1.0,
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    /// Ticks per row used to drive voices, default is module tempo
+    pub fn set_tempo(&mut self, tempo: u16) {
+        if tempo != 0 {
+            self.tempo = tempo;
+        }
+    }
+
+    /// Tick rate is BPM * 0.4, default is module BPM
+    pub fn set_bpm(&mut self, bpm: u16) {
+        if bpm != 0 {
+            self.bpm = bpm;
+        }
+    }
+
+    /// How many voices are currently audible
+    pub fn active_voices(&self) -> usize {
+        self.voices
+            .iter()
+            .filter(|v| v.channel.is_playing())
+            .count()
+    }
+
+    /// Start a note, instrument is an index in `module.instrument`, velocity from 0.0 to 1.0
+    ///
+    /// Returns false if the instrument or the note is invalid.
+    pub fn note_on(&mut self, instrument: usize, note: Note, velocity: f32) -> bool {
+        self.part_note_on(0, instrument, note, velocity).is_some()
+    }
+
+    /// Start a note in a group of voices, returns the voice channel
+    pub(crate) fn part_note_on(
+        &mut self,
+        part: u8,
+        instrument: usize,
+        note: Note,
+        velocity: f32,
+    ) -> Option<&mut Channel<'a>> {
+        if instrument >= self.module.instrument.len() || instrument > 254 || !note.is_valid() {
+            return None;
+        }
+        if self.voices.is_empty() {
+            return None;
+        }
+
+        let index = self.allocate_voice();
+        let age = self.next_age;
+        self.next_age += 1;
+
+        let mut volume = velocity;
+        clamp(&mut volume);
+        let slot = PatternSlot {
+            note,
+            instrument: instrument as u8 + 1,
+            volume: 0x10 + (volume * 64.0) as u8,
+            effect_type: 0,
+            effect_parameter: 0,
+        };
+
+        let voice = &mut self.voices[index];
+        voice.part = part;
+        voice.instrument = instrument;
+        voice.note = note;
+        voice.age = age;
+        voice.released = false;
+        voice.channel.set_pitch_bend(self.pitch_bend);
+        voice.channel.tick0(&slot);
+        Some(&mut voice.channel)
+    }
+
+    /// Release voices of a group playing this note
+    pub(crate) fn part_note_off(&mut self, part: u8, note: Note) {
+        let slot = PatternSlot {
+            note: Note::KeyOff,
+            ..Default::default()
+        };
+        for voice in self.voices.iter_mut() {
+            if !voice.released && voice.part == part && voice.note == note {
+                voice.released = true;
+                voice.channel.tick0(&slot);
+            }
+        }
+    }
+
+    /// Stop voices of a group now, without release
+    pub(crate) fn part_sound_off(&mut self, part: u8) {
+        for voice in self.voices.iter_mut().filter(|v| v.part == part) {
+            voice.released = true;
+            voice.channel.cut();
+        }
+    }
+
+    /// Voices channels of a group
+    pub(crate) fn part_channels(&mut self, part: u8) -> impl Iterator<Item = &mut Channel<'a>> {
+        self.voices
+            .iter_mut()
+            .filter(move |v| v.part == part)
+            .map(|v| &mut v.channel)
+    }
+
+    /// Release all voices playing this note with this instrument
+    pub fn note_off(&mut self, instrument: usize, note: Note) {
+        let slot = PatternSlot {
+            note: Note::KeyOff,
+            ..Default::default()
+        };
+        for voice in self.voices.iter_mut() {
+            if !voice.released && voice.instrument == instrument && voice.note == note {
+                voice.released = true;
+                voice.channel.tick0(&slot);
+            }
+        }
+    }
+
+    /// Release all voices
+    pub fn all_notes_off(&mut self) {
+        let slot = PatternSlot {
+            note: Note::KeyOff,
+            ..Default::default()
+        };
+        for voice in self.voices.iter_mut().filter(|v| !v.released) {
+            voice.released = true;
+            voice.channel.tick0(&slot);
+        }
+    }
+
+    /// Pitch bend in semitones applied to all voices
+    pub fn pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = semitones;
+        for voice in self.voices.iter_mut() {
+            voice.channel.set_pitch_bend(semitones);
+        }
+    }
+
+    /// Free voice first, then the quietest released voice, then the oldest one
+    fn allocate_voice(&self) -> usize {
+        if let Some(i) = self.voices.iter().position(|v| !v.channel.is_playing()) {
+            return i;
+        }
+
+        let released = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.released)
+            .min_by(|(_, a), (_, b)| {
+                a.channel
+                    .get_final_volume()
+                    .total_cmp(&b.channel.get_final_volume())
+            });
+        if let Some((i, _)) = released {
+            return i;
+        }
+
+        self.voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, v)| v.age)
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn tick(&mut self) {
+        let empty = PatternSlot::default();
+        for voice in self.voices.iter_mut() {
+            if self.current_tick == 0 {
+                voice.channel.tick0(&empty);
+            } else {
+                voice.channel.tick(self.current_tick);
+            }
+        }
+
+        self.current_tick += 1;
+        if self.current_tick >= self.tempo {
+            self.current_tick = 0;
+        }
+
+        if let Some(hhelper) = &mut self.hhelper {
+            hhelper.set_tempo(self.tempo);
+        }
+    }
+
+    /// Next frame of each voice into `frames`
+    fn next_frames(&mut self) {
+        if self.remaining_samples_in_tick <= 0.0 {
+            self.tick();
+            /* FT2 manual says number of ticks / second = BPM * 0.4 */
+            self.remaining_samples_in_tick += self.sample_rate / (self.bpm as f32 * 0.4);
+        }
+        self.remaining_samples_in_tick -= 1.0;
+
+        for (frame, voice) in self.frames.iter_mut().zip(self.voices.iter_mut()) {
+            let channel = &mut voice.channel;
+            *frame = if channel.is_silent() {
+                channel.skip();
+                (0.0, 0.0)
+            } else {
+                channel.next().unwrap_or((0.0, 0.0))
+            };
+        }
+    }
+
+    /// Returns samples from each voice before applying global volume and amplification
+    pub fn samples_from_channels(&mut self) -> Vec<(f32, f32)> {
+        self.next_frames();
+        self.frames.clone()
+    }
+
+    /// Applies volume and amplification to the result of `samples_from_channels()`
+    pub fn samples_apply_volume(&self, samples: &[(f32, f32)]) -> (f32, f32) {
+        let sample = samples
+            .iter()
+            .fold((0.0, 0.0), |(acc_left, acc_right), (left, right)| {
+                (acc_left + left, acc_right + right)
+            });
+        let fgvol =
+            (self.global_volume * self.amplification) / (self.global_volume + self.amplification);
+        (sample.0 * fgvol, sample.1 * fgvol)
+    }
+
+    /// Returns next (left,right) sample, without allocating
+    pub fn sample(&mut self) -> (f32, f32) {
+        self.next_frames();
+        self.samples_apply_volume(&self.frames)
+    }
+
+    /// Fill a block of (left,right) samples
+    pub fn render(&mut self, block: &mut [(f32, f32)]) {
+        for frame in block.iter_mut() {
+            *frame = self.sample();
+        }
+    }
+}
@@ -18,12 +18,38 @@ pub(crate) mod effect_vibrato_tremolo;
 pub(crate) mod effect_volume_panning_slide;
 
 pub mod channel;
+pub mod compressed_sample;
+pub mod console;
+pub mod dma_buffer;
+pub mod edit_session;
 pub(crate) mod helper;
 pub(crate) mod historical_helper;
+pub mod instrument_sampler;
+pub mod loop_render;
+#[cfg(feature = "std")]
+pub mod loudness;
+pub mod meter;
+pub mod midi;
+pub mod midi_export;
+pub mod midi_player;
+pub mod overview;
+pub mod pan_law;
+#[cfg(feature = "std")]
+pub mod parallel_render;
+pub mod pattern_source;
+pub mod per_channel;
 pub mod prelude;
+pub mod row_visits;
+pub mod sample_source;
+pub mod scope;
+pub mod sfx;
 pub(crate) mod state_auto_vibrato;
 pub(crate) mod state_envelope;
 pub(crate) mod state_instr_default;
+pub(crate) mod state_midi;
 pub(crate) mod state_sample;
+#[cfg(test)]
+pub(crate) mod test_module;
 
+pub mod voice_budget;
 pub mod xmrsplayer;
@@ -0,0 +1,115 @@
+/// Render a song as an intro and a seamless loop, for game engines
+use crate::xmrsplayer::XmrsPlayer;
+use alloc::{collections::BTreeSet, vec::Vec};
+
+/// Intro and loop buffers, (left, right) samples
+pub struct LoopRender {
+    /// From the start to the end of the first pass of the loop
+    pub intro: Vec<(f32, f32)>,
+    /// Loop body, rendered on its second pass: it continues the end of the intro and its own end
+    pub body: Vec<(f32, f32)>,
+    /// (position in order list, row) where the song loops back
+    pub loop_row: (usize, usize),
+}
+
+impl LoopRender {
+    /// Loop start offset in samples, in a single buffer made of intro and body
+    pub fn loop_start(&self) -> usize {
+        self.intro.len()
+    }
+
+    /// Loop end offset in samples (excluded), in a single buffer made of intro and body
+    pub fn loop_end(&self) -> usize {
+        self.intro.len() + self.body.len()
+    }
+
+    /// Intro followed by body, to be used with `loop_start()` and `loop_end()`
+    pub fn to_single_buffer(&self) -> Vec<(f32, f32)> {
+        let mut buffer = Vec::with_capacity(self.loop_end());
+        buffer.extend_from_slice(&self.intro);
+        buffer.extend_from_slice(&self.body);
+        buffer
+    }
+}
+
+/// Render from current player position until the song loops back (restart position or backward jump)
+///
+/// The loop is found with the rows counted by `row_visits`: the first row played twice starts it.
+/// The loop body is played twice: the first pass ends the intro, the second pass is the body.
+/// Intro followed by body is the same as the player output.
+/// Returns None if no loop is found within `max_samples` samples.
+pub fn render_loop(player: &mut XmrsPlayer, max_samples: usize) -> Option<LoopRender> {
+    let max_loop_count = player.max_loop_count;
+    player.set_max_loop_count(0);
+
+    let mut samples: Vec<(f32, f32)> = Vec::new();
+    // Rows played by this render
+    let mut played: BTreeSet<(usize, usize)> = BTreeSet::new();
+    let mut played_rows = player.played_rows;
+    // (loop row, first sample of second pass)
+    let mut loop_found: Option<((usize, usize), usize)> = None;
+    let mut result = None;
+
+    while samples.len() < max_samples {
+        let Some(sample) = player.sample(true) else {
+            break;
+        };
+        let offset = samples.len();
+        samples.push(sample);
+
+        if player.played_rows == played_rows {
+            continue;
+        }
+        played_rows = player.played_rows;
+        let row = player.played_row;
+
+        match loop_found {
+            None => {
+                if player.loop_count > 0 && played.contains(&row) {
+                    loop_found = Some((row, offset));
+                }
+                played.insert(row);
+            }
+            Some((loop_row, second)) if loop_row == row => {
+                samples.truncate(offset);
+                let body = samples.split_off(second);
+                result = Some(LoopRender {
+                    intro: samples,
+                    body,
+                    loop_row,
+                });
+                break;
+            }
+            Some(_) => {}
+        }
+    }
+
+    player.set_max_loop_count(max_loop_count);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::*;
+
+    #[test]
+    fn intro_and_body_follow_player_output() {
+        let mut module = song(2, 4);
+        module.restart_position = 2;
+
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        let render = render_loop(&mut player, 48000 * 60).unwrap();
+        assert_eq!(render.loop_row, (2, 0));
+        // 16 rows of 6 ticks of 960 samples for each position
+        let position = 16 * 6 * 960;
+        assert_eq!(render.body.len(), 2 * position);
+        assert_eq!(render.intro.len(), 4 * position);
+
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        let output: Vec<(f32, f32)> = (0..render.loop_end())
+            .map(|_| player.sample(true).unwrap())
+            .collect();
+        assert!(render.to_single_buffer() == output);
+    }
+}
@@ -0,0 +1,202 @@
+/// Loudness analysis (EBU R128, ITU-R BS.1770) and ReplayGain values
+use crate::xmrsplayer::XmrsPlayer;
+use alloc::{format, string::String, vec, vec::Vec};
+
+/// ReplayGain 2.0 reference level
+pub const REPLAY_GAIN_REFERENCE: f64 = -18.0;
+
+/// Biquad filter, direct form II
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [[f64; 2]; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, side: usize, x: f64) -> f64 {
+        let z = &mut self.z[side];
+        let w = x - self.a[0] * z[0] - self.a[1] * z[1];
+        let y = self.b[0] * w + self.b[1] * z[0] + self.b[2] * z[1];
+        z[1] = z[0];
+        z[0] = w;
+        y
+    }
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn lufs_to_energy(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// Mean energy of gated blocks: above -70 LUFS, then above mean - `relative` LU
+fn gated(blocks: &[f64], relative: f64) -> Vec<f64> {
+    let absolute = lufs_to_energy(-70.0);
+    let blocks: Vec<f64> = blocks.iter().copied().filter(|&e| e > absolute).collect();
+    if blocks.is_empty() {
+        return blocks;
+    }
+    let mean = blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let threshold = lufs_to_energy(energy_to_lufs(mean) - relative);
+    blocks.into_iter().filter(|&e| e > threshold).collect()
+}
+
+/// Loudness of a stereo signal
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoudnessReport {
+    /// Integrated loudness in LUFS, `f64::NEG_INFINITY` for silence
+    pub integrated: f64,
+    /// Loudness range in LU
+    pub range: f64,
+    /// Highest absolute sample value
+    pub sample_peak: f32,
+}
+
+impl LoudnessReport {
+    /// ReplayGain 2.0 track gain in dB
+    pub fn replay_gain(&self) -> f64 {
+        if self.integrated.is_finite() {
+            REPLAY_GAIN_REFERENCE - self.integrated
+        } else {
+            0.0
+        }
+    }
+
+    /// `REPLAYGAIN_TRACK_GAIN` and `REPLAYGAIN_TRACK_PEAK` tags
+    pub fn replay_gain_tags(&self) -> [(&'static str, String); 2] {
+        [
+            (
+                "REPLAYGAIN_TRACK_GAIN",
+                format!("{:.2} dB", self.replay_gain()),
+            ),
+            ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", self.sample_peak)),
+        ]
+    }
+}
+
+/// K-weighted loudness meter, fed with (left, right) samples
+pub struct LoudnessMeter {
+    filters: [Biquad; 2],
+    /// Samples in a 100 ms block
+    block_length: usize,
+    /// Samples and energy sum of current block
+    count: usize,
+    sum: f64,
+    /// Mean energy of each 100 ms block
+    blocks: Vec<f64>,
+    sample_peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: f32) -> Self {
+        let rate = sample_rate as f64;
+
+        // High shelf
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (core::f64::consts::PI * f0 / rate).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [[0.0; 2]; 2],
+        };
+
+        // High pass
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (core::f64::consts::PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [[0.0; 2]; 2],
+        };
+
+        Self {
+            filters: [shelf, high_pass],
+            block_length: ((rate / 10.0) as usize).max(1),
+            count: 0,
+            sum: 0.0,
+            blocks: vec![],
+            sample_peak: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, sample: (f32, f32)) {
+        self.sample_peak = self.sample_peak.max(sample.0.abs()).max(sample.1.abs());
+        for (side, value) in [sample.0, sample.1].into_iter().enumerate() {
+            let mut x = value as f64;
+            for f in &mut self.filters {
+                x = f.process(side, x);
+            }
+            self.sum += x * x;
+        }
+        self.count += 1;
+        if self.count == self.block_length {
+            self.blocks.push(self.sum / self.count as f64);
+            self.count = 0;
+            self.sum = 0.0;
+        }
+    }
+
+    /// Mean energy of each window of `length` 100 ms blocks, with a 100 ms hop
+    fn windows(&self, length: usize) -> Vec<f64> {
+        self.blocks
+            .windows(length)
+            .map(|w| w.iter().sum::<f64>() / length as f64)
+            .collect()
+    }
+
+    pub fn report(&self) -> LoudnessReport {
+        // 400 ms momentary blocks, 75% overlap
+        let momentary = gated(&self.windows(4), 10.0);
+        let integrated = if momentary.is_empty() {
+            f64::NEG_INFINITY
+        } else {
+            energy_to_lufs(momentary.iter().sum::<f64>() / momentary.len() as f64)
+        };
+
+        // 3 s short-term blocks
+        let mut short_term: Vec<f64> = gated(&self.windows(30), 20.0)
+            .into_iter()
+            .map(energy_to_lufs)
+            .collect();
+        let range = if short_term.len() < 2 {
+            0.0
+        } else {
+            short_term.sort_by(|a, b| a.total_cmp(b));
+            let percentile = |p: f64| short_term[((short_term.len() - 1) as f64 * p) as usize];
+            percentile(0.95) - percentile(0.10)
+        };
+
+        LoudnessReport {
+            integrated,
+            range,
+            sample_peak: self.sample_peak,
+        }
+    }
+}
+
+/// Render the song from current position until `max_loop_count` is reached (one loop if not set)
+/// and measure its loudness, with current volume and amplification
+pub fn analyze(player: &mut XmrsPlayer) -> LoudnessReport {
+    if player.max_loop_count == 0 {
+        player.set_max_loop_count(1);
+    }
+    let mut meter = LoudnessMeter::new(player.get_sample_rate());
+    while let Some(sample) = player.sample(true) {
+        meter.push(sample);
+    }
+    meter.report()
+}
@@ -0,0 +1,168 @@
+/// Level meters, computed by `XmrsPlayer` when enabled with `set_metering()`
+#[cfg(feature = "micromath")]
+#[allow(unused_imports)]
+use micromath::F32Ext;
+#[cfg(feature = "libm")]
+#[allow(unused_imports)]
+use num_traits::float::Float;
+
+use alloc::{vec, vec::Vec};
+
+/// Meter ballistics, times in seconds
+#[derive(Clone, Copy, Debug)]
+pub struct MeterBallistics {
+    /// Peak rise time, 0.0 for instant
+    pub attack: f32,
+    /// Peak fall time
+    pub release: f32,
+    /// RMS integration time
+    pub rms_window: f32,
+    /// How long the peak-hold value stays
+    pub hold: f32,
+}
+
+impl Default for MeterBallistics {
+    fn default() -> Self {
+        Self {
+            attack: 0.0,
+            release: 0.3,
+            rms_window: 0.3,
+            hold: 1.0,
+        }
+    }
+}
+
+/// One pole filter coefficient for a time in seconds
+fn coefficient(time: f32, sample_rate: f32) -> f32 {
+    if time <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-1.0 / (time * sample_rate)).exp()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Coefficients {
+    attack: f32,
+    release: f32,
+    rms: f32,
+    /// Hold time in samples
+    hold: u32,
+}
+
+/// Stereo meter, index 0 is left, 1 is right
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Meter {
+    /// Peak level with ballistics
+    pub peak: [f32; 2],
+    /// Highest peak of the last `hold` seconds
+    pub peak_hold: [f32; 2],
+    hold_counter: [u32; 2],
+    /// Mean square with RMS window
+    mean_square: [f32; 2],
+}
+
+impl Meter {
+    fn update(&mut self, c: &Coefficients, sample: (f32, f32)) {
+        for (side, value) in [sample.0, sample.1].into_iter().enumerate() {
+            let level = value.abs();
+            let peak = &mut self.peak[side];
+            let k = if level > *peak { c.attack } else { c.release };
+            *peak += (level - *peak) * k;
+
+            if level >= self.peak_hold[side] {
+                self.peak_hold[side] = level;
+                self.hold_counter[side] = c.hold;
+            } else if self.hold_counter[side] > 0 {
+                self.hold_counter[side] -= 1;
+            } else {
+                self.peak_hold[side] = *peak;
+            }
+
+            self.mean_square[side] += (value * value - self.mean_square[side]) * c.rms;
+        }
+    }
+
+    /// RMS level
+    pub fn rms(&self) -> [f32; 2] {
+        [self.mean_square[0].sqrt(), self.mean_square[1].sqrt()]
+    }
+}
+
+/// Cubic Hermite interpolation between `y[1]` and `y[2]`
+fn hermite(y: &[f32; 4], t: f32) -> f32 {
+    let c1 = 0.5 * (y[2] - y[0]);
+    let c2 = y[0] - 2.5 * y[1] + 2.0 * y[2] - 0.5 * y[3];
+    let c3 = 0.5 * (y[3] - y[0]) + 1.5 * (y[1] - y[2]);
+    ((c3 * t + c2) * t + c1) * t + y[1]
+}
+
+/// Meters of each channel and of the master output
+#[derive(Clone)]
+pub struct Metering {
+    coefficients: Coefficients,
+    /// Song channels, followed by sound effect voices, before global volume
+    pub channel: Vec<Meter>,
+    /// Output after global volume and amplification
+    pub master: Meter,
+    /// Highest master true peak, 4x oversampled
+    pub true_peak: [f32; 2],
+    /// Master samples above 1.0 (left and right are counted separately)
+    pub clipped_samples: u64,
+    /// Last master samples for oversampling
+    history: [[f32; 4]; 2],
+}
+
+impl Metering {
+    pub fn new(ballistics: MeterBallistics, num_channels: usize, sample_rate: f32) -> Self {
+        let coefficients = Coefficients {
+            attack: coefficient(ballistics.attack, sample_rate),
+            release: coefficient(ballistics.release, sample_rate),
+            rms: coefficient(ballistics.rms_window, sample_rate),
+            hold: (ballistics.hold * sample_rate) as u32,
+        };
+        Self {
+            coefficients,
+            channel: vec![Meter::default(); num_channels],
+            master: Meter::default(),
+            true_peak: [0.0; 2],
+            clipped_samples: 0,
+            history: [[0.0; 4]; 2],
+        }
+    }
+
+    /// Reset true peaks and clipped samples count
+    pub fn reset(&mut self) {
+        self.true_peak = [0.0; 2];
+        self.clipped_samples = 0;
+    }
+
+    pub(crate) fn update_channels(&mut self, samples: &[(f32, f32)]) {
+        if self.channel.len() != samples.len() {
+            self.channel.resize(samples.len(), Meter::default());
+        }
+        for (meter, sample) in self.channel.iter_mut().zip(samples) {
+            meter.update(&self.coefficients, *sample);
+        }
+    }
+
+    pub(crate) fn update_master(&mut self, sample: (f32, f32)) {
+        self.master.update(&self.coefficients, sample);
+        for (side, value) in [sample.0, sample.1].into_iter().enumerate() {
+            if value.abs() > 1.0 {
+                self.clipped_samples += 1;
+            }
+            let h = &mut self.history[side];
+            h.rotate_left(1);
+            h[3] = value;
+            // Interpolated between h[1] and h[2], one sample late
+            let mut peak = h[2].abs();
+            for t in [0.25, 0.5, 0.75] {
+                peak = peak.max(hermite(h, t).abs());
+            }
+            if peak > self.true_peak[side] {
+                self.true_peak[side] = peak;
+            }
+        }
+    }
+}
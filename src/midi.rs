@@ -0,0 +1,621 @@
+/// MIDI output for instruments with MIDI enabled
+use alloc::vec::Vec;
+
+/// A channel voice message sent by the player
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiEvent {
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        key: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// 14 bits value, 0x2000 is centered
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+}
+
+impl MidiEvent {
+    pub const CC_VOLUME: u8 = 7;
+    pub const CC_PAN: u8 = 10;
+
+    /// Returns raw MIDI bytes and how many of them are used
+    pub fn to_bytes(&self) -> ([u8; 3], usize) {
+        match *self {
+            MidiEvent::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => ([0x90 | (channel & 0x0F), key & 0x7F, velocity & 0x7F], 3),
+            MidiEvent::NoteOff { channel, key } => ([0x80 | (channel & 0x0F), key & 0x7F, 0], 3),
+            MidiEvent::ProgramChange { channel, program } => {
+                ([0xC0 | (channel & 0x0F), program & 0x7F, 0], 2)
+            }
+            MidiEvent::PitchBend { channel, value } => (
+                [
+                    0xE0 | (channel & 0x0F),
+                    (value & 0x7F) as u8,
+                    ((value >> 7) & 0x7F) as u8,
+                ],
+                3,
+            ),
+            MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (
+                [0xB0 | (channel & 0x0F), controller & 0x7F, value & 0x7F],
+                3,
+            ),
+        }
+    }
+}
+
+/// Receives MIDI events from `XmrsPlayer`
+///
+/// `frame` is the number of (left,right) samples generated when the event occurs.
+pub trait MidiSink {
+    fn send(&mut self, frame: u64, event: MidiEvent);
+}
+
+impl<T: MidiSink + ?Sized> MidiSink for &mut T {
+    fn send(&mut self, frame: u64, event: MidiEvent) {
+        (**self).send(frame, event);
+    }
+}
+
+/// Useful to keep a handle on the sink given to `XmrsPlayer`
+#[cfg(feature = "std")]
+impl<T: MidiSink + ?Sized> MidiSink for std::sync::Arc<std::sync::Mutex<T>> {
+    fn send(&mut self, frame: u64, event: MidiEvent) {
+        if let Ok(mut sink) = self.lock() {
+            sink.send(frame, event);
+        }
+    }
+}
+
+/// Standard MIDI File sink: records events and writes a type-0 SMF
+pub struct SmfSink {
+    sample_rate: f32,
+    events: Vec<(u64, MidiEvent)>,
+}
+
+impl SmfSink {
+    /// Ticks per quarter note
+    pub const DIVISION: u16 = 960;
+    /// Default SMF tempo is 120 BPM, 2 quarter notes per second
+    const TICKS_PER_SECOND: f64 = Self::DIVISION as f64 * 2.0;
+
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            sample_rate,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn events(&self) -> &[(u64, MidiEvent)] {
+        &self.events
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    fn frame_to_tick(&self, frame: u64) -> u64 {
+        (frame as f64 * Self::TICKS_PER_SECOND / self.sample_rate as f64) as u64
+    }
+
+    /// Returns a complete type-0 Standard MIDI File
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let track: Vec<(u64, MidiEvent)> = self
+            .events
+            .iter()
+            .map(|(frame, event)| (self.frame_to_tick(*frame), *event))
+            .collect();
+
+        let mut smf: Vec<u8> = Vec::new();
+        write_header(&mut smf, 0, 1, Self::DIVISION);
+        write_chunk(&mut smf, b"MTrk", &track_bytes(&[], &track));
+        smf
+    }
+
+    #[cfg(feature = "std")]
+    pub fn write_file(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+}
+
+impl MidiSink for SmfSink {
+    fn send(&mut self, frame: u64, event: MidiEvent) {
+        self.events.push((frame, event));
+    }
+}
+
+/// Returns MTrk data from (tick, meta type, meta data) and (tick, event), both sorted by tick
+pub(crate) fn track_bytes(meta: &[(u64, u8, Vec<u8>)], events: &[(u64, MidiEvent)]) -> Vec<u8> {
+    let mut track: Vec<u8> = Vec::new();
+    let mut last_tick = 0;
+    let mut meta = meta.iter().peekable();
+    let mut events = events.iter().peekable();
+    loop {
+        let next_meta = meta.peek().map(|m| m.0);
+        let next_event = events.peek().map(|e| e.0);
+        let tick = match (next_meta, next_event) {
+            (Some(m), Some(e)) if m <= e => m,
+            (Some(m), None) => m,
+            (_, Some(e)) => e,
+            (None, None) => break,
+        };
+        write_var_len(&mut track, (tick - last_tick) as u32);
+        last_tick = tick;
+        if next_meta == Some(tick) {
+            if let Some((_, kind, data)) = meta.next() {
+                track.extend_from_slice(&[0xFF, *kind]);
+                write_var_len(&mut track, data.len() as u32);
+                track.extend_from_slice(data);
+            }
+        } else if let Some((_, event)) = events.next() {
+            let (bytes, len) = event.to_bytes();
+            track.extend_from_slice(&bytes[..len]);
+        }
+    }
+    // End of track
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    track
+}
+
+pub(crate) fn write_var_len(buf: &mut Vec<u8>, value: u32) {
+    let mut stack = [0u8; 5];
+    let mut n = 0;
+    let mut v = value;
+    loop {
+        stack[n] = (v & 0x7F) as u8;
+        n += 1;
+        v >>= 7;
+        if v == 0 {
+            break;
+        }
+    }
+    while n > 0 {
+        n -= 1;
+        let more = if n > 0 { 0x80 } else { 0x00 };
+        buf.push(stack[n] | more);
+    }
+}
+
+pub(crate) fn write_header(buf: &mut Vec<u8>, format: u16, tracks: u16, division: u16) {
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&format.to_be_bytes());
+    header.extend_from_slice(&tracks.to_be_bytes());
+    header.extend_from_slice(&division.to_be_bytes());
+    write_chunk(buf, b"MThd", &header);
+}
+
+pub(crate) fn write_chunk(buf: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(id);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Error while loading a Standard MIDI File
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiFileError {
+    NotSmf,
+    Truncated,
+    /// SMPTE time division is not supported
+    SmpteDivision,
+}
+
+/// An event from a Standard MIDI File
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmfEvent {
+    Midi(MidiEvent),
+    /// Microseconds per quarter note
+    Tempo(u32),
+}
+
+/// A Standard MIDI File with all tracks merged
+pub struct MidiFile {
+    /// Ticks per quarter note
+    pub division: u16,
+    /// (tick, event) sorted by tick
+    pub events: Vec<(u64, SmfEvent)>,
+}
+
+impl MidiFile {
+    pub fn load(data: &[u8]) -> Result<Self, MidiFileError> {
+        let mut reader = SmfReader { data, pos: 0 };
+        if reader.bytes(4)? != b"MThd" {
+            return Err(MidiFileError::NotSmf);
+        }
+        let header_len = reader.u32()? as usize;
+        let header = reader.bytes(header_len)?;
+        if header.len() < 6 {
+            return Err(MidiFileError::NotSmf);
+        }
+        let tracks = u16::from_be_bytes([header[2], header[3]]);
+        let division = u16::from_be_bytes([header[4], header[5]]);
+        if division & 0x8000 != 0 {
+            return Err(MidiFileError::SmpteDivision);
+        }
+
+        let mut events: Vec<(u64, SmfEvent)> = Vec::new();
+        for _ in 0..tracks {
+            let id = reader.bytes(4)?;
+            let len = reader.u32()? as usize;
+            let chunk = reader.bytes(len)?;
+            if id == b"MTrk" {
+                Self::load_track(chunk, &mut events)?;
+            }
+        }
+        events.sort_by_key(|(tick, _)| *tick);
+
+        Ok(Self { division, events })
+    }
+
+    fn load_track(data: &[u8], events: &mut Vec<(u64, SmfEvent)>) -> Result<(), MidiFileError> {
+        let mut reader = SmfReader { data, pos: 0 };
+        let mut tick: u64 = 0;
+        let mut running_status = 0u8;
+        while !reader.is_empty() {
+            tick += reader.var_len()? as u64;
+            let mut status = reader.u8()?;
+            match status {
+                0xFF => {
+                    let meta = reader.u8()?;
+                    let len = reader.var_len()? as usize;
+                    let data = reader.bytes(len)?;
+                    match meta {
+                        0x2F => break,
+                        0x51 if len == 3 => {
+                            let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                            events.push((tick, SmfEvent::Tempo(tempo)));
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                0xF0 | 0xF7 => {
+                    let len = reader.var_len()? as usize;
+                    reader.bytes(len)?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let data1 = if status & 0x80 == 0 {
+                // Running status: first data byte already read
+                let data1 = status;
+                status = running_status;
+                data1
+            } else {
+                running_status = status;
+                reader.u8()?
+            };
+            let channel = status & 0x0F;
+            let event = match status & 0xF0 {
+                0x80 => Some(MidiEvent::NoteOff {
+                    channel,
+                    key: data1,
+                }),
+                0x90 => {
+                    let velocity = reader.u8()?;
+                    if velocity == 0 {
+                        Some(MidiEvent::NoteOff {
+                            channel,
+                            key: data1,
+                        })
+                    } else {
+                        Some(MidiEvent::NoteOn {
+                            channel,
+                            key: data1,
+                            velocity,
+                        })
+                    }
+                }
+                0xB0 => Some(MidiEvent::ControlChange {
+                    channel,
+                    controller: data1,
+                    value: reader.u8()?,
+                }),
+                0xC0 => Some(MidiEvent::ProgramChange {
+                    channel,
+                    program: data1,
+                }),
+                0xE0 => Some(MidiEvent::PitchBend {
+                    channel,
+                    value: (data1 as u16 & 0x7F) | ((reader.u8()? as u16 & 0x7F) << 7),
+                }),
+                0xD0 => None,
+                0xA0 => {
+                    reader.u8()?;
+                    None
+                }
+                _ => return Err(MidiFileError::NotSmf),
+            };
+            if status & 0xF0 == 0x80 {
+                // Note off velocity
+                reader.u8()?;
+            }
+            if let Some(event) = event {
+                events.push((tick, SmfEvent::Midi(event)));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct SmfReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SmfReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], MidiFileError> {
+        if self.pos + len > self.data.len() {
+            return Err(MidiFileError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiFileError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiFileError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn var_len(&mut self) -> Result<u32, MidiFileError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = (value << 7) | (b & 0x7F) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MidiFileError::NotSmf)
+    }
+}
+
+/// Live MIDI output using `midir`
+#[cfg(feature = "midir")]
+pub struct MidirSink {
+    connection: midir::MidiOutputConnection,
+}
+
+#[cfg(feature = "midir")]
+impl MidirSink {
+    pub fn new(connection: midir::MidiOutputConnection) -> Self {
+        Self { connection }
+    }
+
+    /// Connect to the first output port whose name contains `port_name`
+    pub fn connect(port_name: &str) -> Option<Self> {
+        let output = midir::MidiOutput::new("xmrsplayer").ok()?;
+        let port = output
+            .ports()
+            .into_iter()
+            .find(|p| output.port_name(p).is_ok_and(|n| n.contains(port_name)))?;
+        let connection = output.connect(&port, "xmrsplayer").ok()?;
+        Some(Self::new(connection))
+    }
+
+    pub fn close(self) -> midir::MidiOutput {
+        self.connection.close()
+    }
+}
+
+#[cfg(feature = "midir")]
+impl MidiSink for MidirSink {
+    fn send(&mut self, _frame: u64, event: MidiEvent) {
+        let (bytes, len) = event.to_bytes();
+        let _ = self.connection.send(&bytes[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Header and tracks of a type 1 file, 96 ticks per quarter note
+    fn smf(tracks: &[&[u8]]) -> Vec<u8> {
+        let mut smf = vec![];
+        write_header(&mut smf, 1, tracks.len() as u16, 96);
+        for track in tracks {
+            write_chunk(&mut smf, b"MTrk", track);
+        }
+        smf
+    }
+
+    fn midi(tick: u64, event: MidiEvent) -> (u64, SmfEvent) {
+        (tick, SmfEvent::Midi(event))
+    }
+
+    #[test]
+    fn load_merges_tracks() {
+        let tempo_track: &[u8] = &[
+            0x00, 0xFF, 0x03, 0x04, b'S', b'o', b'n', b'g', // track name
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 500000 us per quarter note
+            0x81, 0x00, 0xFF, 0x51, 0x03, 0x03, 0xD0, 0x90, // 250000 at tick 128
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let note_track: &[u8] = &[
+            0x00, 0x91, 0x3C, 0x64, // note on
+            0x0A, 0x40, 0x50, // running status note on
+            0x05, 0x3C, 0x00, // running status note on, velocity 0 is a note off
+            0x00, 0x81, 0x40, 0x7F, // note off with velocity
+            0x00, 0xF0, 0x03, 0x7E, 0x09, 0xF7, // sysex, skipped
+            0x00, 0xA1, 0x40, 0x10, // polyphonic aftertouch, skipped
+            0x00, 0xD1, 0x10, // channel aftertouch, skipped
+            0x00, 0xB1, 0x07, 0x64, // volume
+            0x00, 0xC1, 0x05, // program change
+            0x00, 0x06, // running status program change
+            0x81, 0x00, 0xE1, 0x00, 0x40, // pitch bend center, at tick 143
+            0x00, 0xFF, 0x2F, 0x00, 0x00, 0x91, 0x3C, 0x64, // after end of track, ignored
+        ];
+        let file = MidiFile::load(&smf(&[tempo_track, note_track])).unwrap();
+        assert_eq!(file.division, 96);
+        let channel = 1;
+        assert_eq!(
+            file.events,
+            vec![
+                (0, SmfEvent::Tempo(500_000)),
+                midi(
+                    0,
+                    MidiEvent::NoteOn {
+                        channel,
+                        key: 0x3C,
+                        velocity: 0x64
+                    }
+                ),
+                midi(
+                    10,
+                    MidiEvent::NoteOn {
+                        channel,
+                        key: 0x40,
+                        velocity: 0x50
+                    }
+                ),
+                midi(15, MidiEvent::NoteOff { channel, key: 0x3C }),
+                midi(15, MidiEvent::NoteOff { channel, key: 0x40 }),
+                midi(
+                    15,
+                    MidiEvent::ControlChange {
+                        channel,
+                        controller: MidiEvent::CC_VOLUME,
+                        value: 0x64
+                    }
+                ),
+                midi(
+                    15,
+                    MidiEvent::ProgramChange {
+                        channel,
+                        program: 5
+                    }
+                ),
+                midi(
+                    15,
+                    MidiEvent::ProgramChange {
+                        channel,
+                        program: 6
+                    }
+                ),
+                (128, SmfEvent::Tempo(250_000)),
+                midi(
+                    143,
+                    MidiEvent::PitchBend {
+                        channel,
+                        value: 0x2000
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn sink_file_loads_back() {
+        let mut sink = SmfSink::new(48000.0);
+        let events = [
+            MidiEvent::ProgramChange {
+                channel: 2,
+                program: 9,
+            },
+            MidiEvent::NoteOn {
+                channel: 2,
+                key: 70,
+                velocity: 90,
+            },
+            MidiEvent::NoteOff {
+                channel: 2,
+                key: 70,
+            },
+        ];
+        for (i, event) in events.iter().enumerate() {
+            // Half a second is a quarter note at 120 BPM
+            sink.send(i as u64 * 24000, *event);
+        }
+        let file = MidiFile::load(&sink.to_bytes()).unwrap();
+        assert_eq!(file.division, SmfSink::DIVISION);
+        let loaded: Vec<(u64, SmfEvent)> = events
+            .iter()
+            .enumerate()
+            .map(|(i, e)| midi(i as u64 * SmfSink::DIVISION as u64, *e))
+            .collect();
+        assert_eq!(file.events, loaded);
+    }
+
+    #[test]
+    fn malformed_files() {
+        let end: &[u8] = &[0x00, 0xFF, 0x2F, 0x00];
+        let valid = smf(&[end]);
+        assert!(MidiFile::load(&valid).is_ok());
+
+        assert_eq!(MidiFile::load(&[]).err(), Some(MidiFileError::Truncated));
+        assert_eq!(
+            MidiFile::load(b"RIFF\0\0\0\x06\0\x01\0\x01\0\x60").err(),
+            Some(MidiFileError::NotSmf)
+        );
+        // Header shorter than its length, or too short
+        assert_eq!(
+            MidiFile::load(&valid[..10]).err(),
+            Some(MidiFileError::Truncated)
+        );
+        assert_eq!(
+            MidiFile::load(b"MThd\0\0\0\x04\0\x01\0\x01").err(),
+            Some(MidiFileError::NotSmf)
+        );
+        assert_eq!(
+            MidiFile::load(b"MThd\0\0\0\x06\0\x01\0\x01\xE7\x28").err(),
+            Some(MidiFileError::SmpteDivision)
+        );
+        // Missing track, track shorter than its length
+        assert_eq!(
+            MidiFile::load(&valid[..14]).err(),
+            Some(MidiFileError::Truncated)
+        );
+        assert_eq!(
+            MidiFile::load(&valid[..valid.len() - 1]).err(),
+            Some(MidiFileError::Truncated)
+        );
+
+        for (track, error) in [
+            // Event cut before its last data byte
+            (&[0x00, 0x90, 0x3C][..], MidiFileError::Truncated),
+            // Meta event longer than the track
+            (
+                &[0x00, 0xFF, 0x01, 0x05, b'a'][..],
+                MidiFileError::Truncated,
+            ),
+            // Delta time of more than 4 bytes
+            (&[0x80, 0x80, 0x80, 0x80, 0x00][..], MidiFileError::NotSmf),
+            // Data byte without running status
+            (&[0x00, 0x3C, 0x64][..], MidiFileError::NotSmf),
+        ] {
+            assert_eq!(MidiFile::load(&smf(&[track])).err(), Some(error));
+        }
+    }
+}
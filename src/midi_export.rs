@@ -0,0 +1,172 @@
+/// Export song playback as a Standard MIDI File
+use crate::midi::{track_bytes, write_chunk, write_header, MidiEvent, MidiSink};
+use crate::xmrsplayer::XmrsPlayer;
+use alloc::{format, vec, vec::Vec};
+
+/// Ticks per quarter note
+const DIVISION: u16 = 960;
+
+/// Collect events of one track
+#[derive(Default)]
+struct TrackSink {
+    events: Vec<(u64, MidiEvent)>,
+}
+
+impl MidiSink for TrackSink {
+    fn send(&mut self, frame: u64, event: MidiEvent) {
+        self.events.push((frame, event));
+    }
+}
+
+/// Returns a type-1 Standard MIDI File, one track for each tracker channel
+///
+/// The song is played by the sequencer from current player position, without generating samples,
+/// until `max_loop_count` is reached (one loop if not set). Channels muted in the module or by the
+/// console (mute, solo, muted instruments) are not exported, as they are not heard.
+///
+/// A row is a sixteenth note: MIDI tempo follows both tracker tempo (speed) and BPM.
+/// MIDI channel is tracker channel modulo 16 and program is the instrument number.
+/// The MIDI sink of the player, if any, gets no event during export.
+pub fn export_midi(player: &mut XmrsPlayer) -> Vec<u8> {
+    let max_loop_count = player.max_loop_count;
+    if max_loop_count == 0 {
+        player.set_max_loop_count(1);
+    }
+    // The sink would take note triggers before the exported tracks
+    let sink = player.take_midi_sink();
+
+    let num_channels = player.channel.len();
+    let mut tracks: Vec<TrackSink> = (0..num_channels).map(|_| TrackSink::default()).collect();
+    let mut tempo_map: Vec<(u64, u8, Vec<u8>)> = vec![];
+    let mut last_tempo = None;
+    // Position in MIDI ticks
+    let mut position: f64 = 0.0;
+
+    loop {
+        player.sequence_tick();
+        if player.is_finished() {
+            break;
+        }
+
+        let tempo = player.get_tempo().max(1);
+        let bpm = player.get_bpm().max(1);
+        let tick = (position + 0.5) as u64;
+        if last_tempo != Some((tempo, bpm)) {
+            // A quarter note is 4 rows
+            let us_per_quarter = (10_000_000 * tempo / bpm) as u32;
+            tempo_map.push((tick, 0x51, us_per_quarter.to_be_bytes()[1..].to_vec()));
+            last_tempo = Some((tempo, bpm));
+        }
+
+        for (ch_index, (ch, track)) in player.channel.iter_mut().zip(&mut tracks).enumerate() {
+            let muted = player.console.is_muted(ch_index, ch.instrument_num());
+            if ch.muted || ch.disabled || muted {
+                ch.midi_release(tick, track);
+            } else {
+                ch.midi_tick(tick, track, Some((ch_index % 16) as u8));
+            }
+        }
+
+        player.end_tick();
+
+        // A tracker tick is 1/(4*tempo) quarter note
+        position += DIVISION as f64 / (4 * tempo) as f64;
+    }
+
+    let end = (position + 0.5) as u64;
+    let mut smf: Vec<u8> = Vec::new();
+    write_header(&mut smf, 1, num_channels as u16 + 1, DIVISION);
+    tempo_map.insert(0, (0, 0x03, player.module.name.as_bytes().to_vec()));
+    write_chunk(&mut smf, b"MTrk", &track_bytes(&tempo_map, &[]));
+    for (ch_index, (ch, mut track)) in player.channel.iter_mut().zip(tracks).enumerate() {
+        ch.midi_release(end, &mut track);
+        let name = format!("Channel {}", ch_index + 1);
+        let meta = [(0, 0x03, name.into_bytes())];
+        write_chunk(&mut smf, b"MTrk", &track_bytes(&meta, &track.events));
+    }
+    player.set_midi_sink(sink);
+    player.set_max_loop_count(max_loop_count);
+    smf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{MidiFile, SmfEvent};
+    use crate::test_module::*;
+    use xmrs::prelude::*;
+
+    /// (tick, MIDI channel, key) of note-ons, and the number of note-offs
+    fn notes(smf: &[u8]) -> (Vec<(u64, u8, u8)>, usize) {
+        let file = MidiFile::load(smf).unwrap();
+        assert_eq!(file.division, DIVISION);
+        let mut offs = 0;
+        let mut ons = vec![];
+        for (tick, event) in file.events {
+            match event {
+                SmfEvent::Midi(MidiEvent::NoteOn { channel, key, .. }) => {
+                    ons.push((tick, channel, key))
+                }
+                SmfEvent::Midi(MidiEvent::NoteOff { .. }) => offs += 1,
+                _ => {}
+            }
+        }
+        (ons, offs)
+    }
+
+    /// Notes of `channels` in the song, a row is a sixteenth note
+    fn song_notes(module: &Module, channels: &[usize]) -> Vec<(u64, u8, u8)> {
+        let mut notes = vec![];
+        for (p, &pattern) in module.pattern_order.iter().enumerate() {
+            for (r, row) in module.pattern[pattern].iter().enumerate() {
+                for &c in channels {
+                    if row[c].note.is_valid() {
+                        let tick = (p * 16 + r) as u64 * DIVISION as u64 / 4;
+                        notes.push((tick, c as u8, row[c].note.value() + 11));
+                    }
+                }
+            }
+        }
+        notes
+    }
+
+    #[test]
+    fn exported_file_loads_back() {
+        let module = song(3, 2);
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        let smf = export_midi(&mut player);
+        assert_eq!(player.max_loop_count, 0);
+
+        // Tempo 6 at 125 BPM: 4 rows in 0.48 s
+        let file = MidiFile::load(&smf).unwrap();
+        assert_eq!(file.events[0], (0, SmfEvent::Tempo(480_000)));
+        let (ons, offs) = notes(&smf);
+        assert_eq!(ons, song_notes(&module, &[0, 1, 2]));
+        assert_eq!(offs, ons.len());
+    }
+
+    #[test]
+    fn console_mutes_are_not_exported() {
+        let module = song(3, 2);
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        player.console.set_mute(1, true);
+        let (ons, offs) = notes(&export_midi(&mut player));
+        assert_eq!(ons, song_notes(&module, &[0, 2]));
+        assert_eq!(offs, ons.len());
+
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        player.console.set_solo(2, true);
+        assert_eq!(
+            notes(&export_midi(&mut player)).0,
+            song_notes(&module, &[2])
+        );
+
+        // Instrument 2 is played by channel 1
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        player.console.mute_instrument(1, true);
+        assert_eq!(
+            notes(&export_midi(&mut player)).0,
+            song_notes(&module, &[0, 2])
+        );
+    }
+}
@@ -0,0 +1,301 @@
+/// Play a Standard MIDI File through module instruments
+use crate::instrument_sampler::InstrumentSampler;
+use crate::midi::{MidiEvent, MidiFile, SmfEvent};
+use alloc::vec::Vec;
+use xmrs::prelude::*;
+
+#[derive(Clone, Copy)]
+struct MidiChannelState {
+    program: u8,
+    /// Pitch bend in semitones
+    bend: f32,
+    /// CC7: 0.0 to 1.0
+    volume: f32,
+    /// CC10: 0.0 (left) to 1.0 (right), None to keep sample panning
+    panning: Option<f32>,
+}
+
+impl Default for MidiChannelState {
+    fn default() -> Self {
+        Self {
+            program: 0,
+            bend: 0.0,
+            volume: 100.0 / 127.0,
+            panning: None,
+        }
+    }
+}
+
+pub struct MidiPlayer<'a> {
+    module: &'a Module,
+    file: MidiFile,
+    sampler: InstrumentSampler<'a>,
+
+    /// Next event to play
+    event_index: usize,
+    /// Current position in MIDI ticks
+    position: f64,
+    /// MIDI ticks per output sample
+    ticks_per_sample: f64,
+
+    channels: [MidiChannelState; 16],
+    /// Instrument for each MIDI program
+    program_map: [Option<usize>; 128],
+    /// Instrument for each MIDI channel, overriding programs
+    channel_map: [Option<usize>; 16],
+    /// Pitch bend range in semitones
+    pub bend_range: f32,
+}
+
+impl<'a> MidiPlayer<'a> {
+    pub fn new(
+        module: &'a Module,
+        file: MidiFile,
+        sample_rate: f32,
+        historical: bool,
+        max_voices: usize,
+    ) -> Self {
+        let mut player = Self {
+            module,
+            file,
+            sampler: InstrumentSampler::new(module, sample_rate, historical, max_voices),
+            event_index: 0,
+            position: 0.0,
+            ticks_per_sample: 0.0,
+            channels: [MidiChannelState::default(); 16],
+            program_map: [None; 128],
+            channel_map: [None; 16],
+            bend_range: 2.0,
+        };
+        // SMF default tempo is 120 BPM
+        player.set_tempo(500_000);
+        player
+    }
+
+    /// Access to the sampler, for example to change amplification
+    pub fn sampler(&mut self) -> &mut InstrumentSampler<'a> {
+        &mut self.sampler
+    }
+
+    /// Play a MIDI program with an instrument (index in `module.instrument`)
+    pub fn map_program(&mut self, program: u8, instrument: usize) {
+        self.program_map[program as usize & 0x7F] = Some(instrument);
+    }
+
+    /// Play all notes of a MIDI channel with an instrument, whatever the program
+    pub fn map_channel(&mut self, channel: u8, instrument: Option<usize>) {
+        self.channel_map[channel as usize & 0x0F] = instrument;
+    }
+
+    fn instrument(&self, channel: u8) -> usize {
+        if let Some(i) = self.channel_map[channel as usize] {
+            return i;
+        }
+        let program = self.channels[channel as usize].program;
+        match self.program_map[program as usize] {
+            Some(i) => i,
+            None if (program as usize) < self.module.instrument.len() => program as usize,
+            None => 0,
+        }
+    }
+
+    /// tempo in microseconds per quarter note
+    fn set_tempo(&mut self, tempo: u32) {
+        let seconds_per_tick = tempo as f64 / 1_000_000.0 / self.file.division.max(1) as f64;
+        self.ticks_per_sample = 1.0 / (seconds_per_tick * self.sampler.get_sample_rate() as f64);
+    }
+
+    /// MIDI key 60 is C-4
+    fn key_to_note(key: u8) -> Option<Note> {
+        Note::try_from(key.checked_sub(11)?)
+            .ok()
+            .filter(|n| n.is_valid())
+    }
+
+    fn event(&mut self, event: MidiEvent) {
+        match event {
+            MidiEvent::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => {
+                let Some(note) = Self::key_to_note(key) else {
+                    return;
+                };
+                let instrument = self.instrument(channel);
+                let state = self.channels[channel as usize];
+                if let Some(ch) =
+                    self.sampler
+                        .part_note_on(channel, instrument, note, velocity as f32 / 127.0)
+                {
+                    ch.set_pitch_bend(state.bend);
+                    ch.set_channel_volume(state.volume);
+                    if let Some(panning) = state.panning {
+                        ch.set_panning(panning);
+                    }
+                    ch.tickn_update_instr();
+                }
+            }
+            MidiEvent::NoteOff { channel, key } => {
+                if let Some(note) = Self::key_to_note(key) {
+                    self.sampler.part_note_off(channel, note);
+                }
+            }
+            MidiEvent::ProgramChange { channel, program } => {
+                self.channels[channel as usize].program = program;
+            }
+            MidiEvent::PitchBend { channel, value } => {
+                let bend = (value as f32 - 8192.0) / 8192.0 * self.bend_range;
+                self.channels[channel as usize].bend = bend;
+                for ch in self.sampler.part_channels(channel) {
+                    ch.set_pitch_bend(bend);
+                }
+            }
+            MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            } => {
+                let value = value as f32 / 127.0;
+                match controller {
+                    MidiEvent::CC_VOLUME => {
+                        self.channels[channel as usize].volume = value;
+                        for ch in self.sampler.part_channels(channel) {
+                            ch.set_channel_volume(value);
+                        }
+                    }
+                    MidiEvent::CC_PAN => {
+                        self.channels[channel as usize].panning = Some(value);
+                        for ch in self.sampler.part_channels(channel) {
+                            ch.set_panning(value);
+                        }
+                    }
+                    // All sound off: voices are cut
+                    120 => self.sampler.part_sound_off(channel),
+                    // All notes off: voices are released
+                    123 => {
+                        for key in 0..128u8 {
+                            if let Some(note) = Self::key_to_note(key) {
+                                self.sampler.part_note_off(channel, note);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// True when all events are played and all voices are silent
+    pub fn is_finished(&self) -> bool {
+        self.event_index >= self.file.events.len() && self.sampler.active_voices() == 0
+    }
+
+    /// Play events up to the current position, returns false when finished
+    fn play_events(&mut self) -> bool {
+        while let Some((tick, event)) = self.file.events.get(self.event_index).copied() {
+            if tick as f64 > self.position {
+                break;
+            }
+            match event {
+                SmfEvent::Midi(e) => self.event(e),
+                SmfEvent::Tempo(t) => self.set_tempo(t),
+            }
+            self.event_index += 1;
+        }
+        if self.is_finished() {
+            return false;
+        }
+        self.position += self.ticks_per_sample;
+        true
+    }
+
+    /// Returns samples from each voice before applying global volume and amplification.
+    /// If the function returns None, no more samples are available.
+    pub fn samples_from_channels(&mut self) -> Option<Vec<(f32, f32)>> {
+        if !self.play_events() {
+            return None;
+        }
+        Some(self.sampler.samples_from_channels())
+    }
+
+    /// Applies volume and amplification to the result of `samples_from_channels()`
+    pub fn samples_apply_volume(&self, samples: &[(f32, f32)]) -> (f32, f32) {
+        self.sampler.samples_apply_volume(samples)
+    }
+
+    /// Returns next (left,right) sample, without allocating
+    pub fn sample(&mut self) -> Option<(f32, f32)> {
+        if !self.play_events() {
+            return None;
+        }
+        Some(self.sampler.sample())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{track_bytes, write_chunk, write_header};
+    use crate::test_module::*;
+    use alloc::vec;
+
+    /// Type 0 file of `events`, 96 ticks per quarter note
+    fn file(events: &[(u64, MidiEvent)]) -> MidiFile {
+        let mut smf = vec![];
+        write_header(&mut smf, 0, 1, 96);
+        write_chunk(&mut smf, b"MTrk", &track_bytes(&[], events));
+        MidiFile::load(&smf).unwrap()
+    }
+
+    /// Active voices after a note and then `controller`
+    fn voices_after(controller: u8) -> usize {
+        let mut module = module(vec![wave(2000)], vec![pattern(1, 1)], vec![0]);
+        // Sustained volume envelope without fadeout: a released voice goes on playing
+        if let InstrumentType::Default(instr) = &mut module.instrument[0].instr_type {
+            instr.volume_envelope.enabled = true;
+            instr.volume_envelope.point = vec![
+                EnvelopePoint {
+                    frame: 0,
+                    value: 1.0,
+                },
+                EnvelopePoint {
+                    frame: 10,
+                    value: 1.0,
+                },
+            ];
+            instr.volume_envelope.sustain_enabled = true;
+        }
+        let events = [
+            (
+                0,
+                MidiEvent::NoteOn {
+                    channel: 0,
+                    key: 60,
+                    velocity: 100,
+                },
+            ),
+            (
+                96,
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller,
+                    value: 0,
+                },
+            ),
+        ];
+        let mut player = MidiPlayer::new(&module, file(&events), 48000.0, false, 4);
+        // A quarter note lasts 0.5 s, until just after the controller
+        for _ in 0..24010 {
+            player.sample();
+        }
+        player.sampler().active_voices()
+    }
+
+    #[test]
+    fn all_sound_off_cuts_voices() {
+        assert_eq!(voices_after(MidiEvent::CC_PAN), 1);
+        assert_eq!(voices_after(123), 1);
+        assert_eq!(voices_after(120), 0);
+    }
+}
@@ -0,0 +1,178 @@
+/// Waveform overview of a whole song, for scrub bars
+#[cfg(feature = "micromath")]
+#[allow(unused_imports)]
+use micromath::F32Ext;
+#[cfg(feature = "libm")]
+#[allow(unused_imports)]
+use num_traits::float::Float;
+
+use crate::voice_budget::VoiceBudget;
+use crate::xmrsplayer::XmrsPlayer;
+use alloc::{vec, vec::Vec};
+use xmrs::prelude::*;
+
+/// Levels of a part of the song, (left + right) / 2
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OverviewBucket {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+/// Song duration in samples, from the beginning to `max_loop_count` loops
+///
+/// Only the sequencer is played, sample timing is the one of `XmrsPlayer::step()`.
+pub fn song_duration(module: &Module, sample_rate: f32, historical: bool, loops: usize) -> u64 {
+    let mut player = XmrsPlayer::new(module, sample_rate, historical);
+    player.set_max_loop_count(loops.max(1));
+    let mut duration = 0;
+    loop {
+        player.step();
+        if player.is_finished() {
+            return duration;
+        }
+        duration += 1;
+    }
+}
+
+/// Levels of consecutive samples, merged into buckets at the end
+#[derive(Clone, Copy, Default)]
+struct Block {
+    min: f32,
+    max: f32,
+    /// Sum of squares
+    sum: f32,
+    count: u64,
+}
+
+impl Block {
+    fn add(&mut self, value: f32) {
+        self.merge(&Block {
+            min: value,
+            max: value,
+            sum: value * value,
+            count: 1,
+        });
+    }
+
+    fn merge(&mut self, other: &Block) {
+        if self.count == 0 {
+            *self = *other;
+        } else if other.count != 0 {
+            self.min = self.min.min(other.min);
+            self.max = self.max.max(other.max);
+            self.sum += other.sum;
+            self.count += other.count;
+        }
+    }
+}
+
+/// Returns `buckets` levels covering one loop of the song
+///
+/// The song is rendered once at `internal_rate` (for example 8000.0) with nearest neighbour sampling
+/// and silent voices skipped by a `VoiceBudget`, much faster than full quality rendering.
+/// Levels are kept by blocks of samples, merged two by two when the song goes on,
+/// so bucket bounds are exact within 1/8 of a bucket.
+pub fn waveform_overview(
+    module: &Module,
+    historical: bool,
+    amplification: f32,
+    buckets: usize,
+    internal_rate: f32,
+) -> Vec<OverviewBucket> {
+    let mut overview = vec![OverviewBucket::default(); buckets];
+    if buckets == 0 {
+        return overview;
+    }
+
+    let mut player = XmrsPlayer::new(module, internal_rate, historical);
+    player.amplification = amplification;
+    player.set_interpolation(false);
+    player.set_voice_budget(Some(VoiceBudget::default()));
+    player.set_max_loop_count(1);
+
+    // Blocks of `block_len` samples
+    let max_blocks = 16 * buckets;
+    let mut blocks: Vec<Block> = Vec::with_capacity(max_blocks);
+    let mut block_len = 1;
+    let mut current = Block::default();
+    let mut frames = [(0.0, 0.0); 256];
+    loop {
+        let written = player.render(&mut frames);
+        for &(left, right) in &frames[..written] {
+            current.add((left + right) * 0.5);
+            if current.count == block_len {
+                blocks.push(current);
+                current = Block::default();
+                if blocks.len() == max_blocks {
+                    for i in 0..max_blocks / 2 {
+                        let mut block = blocks[2 * i];
+                        block.merge(&blocks[2 * i + 1]);
+                        blocks[i] = block;
+                    }
+                    blocks.truncate(max_blocks / 2);
+                    block_len *= 2;
+                }
+            }
+        }
+        if written < frames.len() {
+            break;
+        }
+    }
+    if current.count > 0 {
+        blocks.push(current);
+    }
+
+    let count = blocks.len();
+    if count == 0 {
+        return overview;
+    }
+    for (i, bucket) in overview.iter_mut().enumerate() {
+        let from = i * count / buckets;
+        let to = ((i + 1) * count / buckets).max(from + 1);
+        let mut level = Block::default();
+        for block in &blocks[from..to] {
+            level.merge(block);
+        }
+        *bucket = OverviewBucket {
+            min: level.min,
+            max: level.max,
+            rms: (level.sum / level.count as f32).sqrt(),
+        };
+    }
+    overview
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::*;
+
+    #[test]
+    fn overview_covers_the_song() {
+        let module = song(4, 4);
+        let overview = waveform_overview(&module, false, 1.0, 10, 8000.0);
+
+        let mut player = XmrsPlayer::new(&module, 8000.0, false);
+        player.set_interpolation(false);
+        player.set_max_loop_count(1);
+        let mut values = vec![];
+        while let Some((left, right)) = player.sample(true) {
+            values.push((left + right) * 0.5);
+        }
+        assert_eq!(
+            values.len() as u64,
+            song_duration(&module, 8000.0, false, 1)
+        );
+
+        let min = values.iter().copied().fold(f32::MAX, f32::min);
+        let max = values.iter().copied().fold(f32::MIN, f32::max);
+        let overview_min = overview.iter().map(|b| b.min).fold(f32::MAX, f32::min);
+        let overview_max = overview.iter().map(|b| b.max).fold(f32::MIN, f32::max);
+        assert!((min - overview_min).abs() < 1e-3 && (max - overview_max).abs() < 1e-3);
+        for bucket in &overview {
+            assert!(bucket.min <= bucket.max);
+            assert!(bucket.rms > 0.0 && bucket.rms <= bucket.max.max(-bucket.min));
+        }
+    }
+}
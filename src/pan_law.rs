@@ -0,0 +1,79 @@
+/// Pan laws and volume curves used by channels
+#[cfg(feature = "micromath")]
+#[allow(unused_imports)]
+use micromath::F32Ext;
+#[cfg(feature = "libm")]
+#[allow(unused_imports)]
+use num_traits::float::Float;
+
+/// How panning is turned into (left, right) gains
+///
+/// Centre-panned gains are 0.707 for `ConstantPower` and `Ft2`, 0.5 for `Linear` and 1.0 for `Amiga`:
+///
+/// ```
+/// use xmrsplayer::pan_law::PanLaw;
+///
+/// for (law, level) in [
+///     (PanLaw::ConstantPower, 0.707),
+///     (PanLaw::Linear, 0.5),
+///     (PanLaw::Ft2, 0.707),
+///     (PanLaw::Amiga, 1.0),
+/// ] {
+///     let gains = law.gains(0.5);
+///     // micromath square root is an approximation
+///     assert!((gains[0] - level).abs() < 0.05 && gains[0] == gains[1]);
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanLaw {
+    /// Square root, same power everywhere
+    #[default]
+    ConstantPower,
+    /// Impulse Tracker like linear pan
+    Linear,
+    /// FastTracker 2 square root table, 256 steps
+    Ft2,
+    /// Hard pan: each side is on or off
+    Amiga,
+}
+
+impl PanLaw {
+    /// Gains for a panning between 0.0 and 1.0
+    pub fn gains(&self, panning: f32) -> [f32; 2] {
+        let panning = panning.clamp(0.0, 1.0);
+        match self {
+            PanLaw::ConstantPower => [panning.sqrt(), (1.0 - panning).sqrt()],
+            PanLaw::Linear => [panning, 1.0 - panning],
+            PanLaw::Ft2 => {
+                let step = (panning * 256.0) as u32;
+                [
+                    (step as f32 / 256.0).sqrt(),
+                    ((256 - step) as f32 / 256.0).sqrt(),
+                ]
+            }
+            PanLaw::Amiga => [
+                if panning >= 0.5 { 1.0 } else { 0.0 },
+                if panning <= 0.5 { 1.0 } else { 0.0 },
+            ],
+        }
+    }
+}
+
+/// How note volume, volume envelope and fadeout are chained
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VolumeCurve {
+    /// `(a*b*c)/(a+b+c)`, historical XmRsPlayer behaviour, a third of the volume at full scale
+    #[default]
+    Legacy,
+    /// `a*b*c`, like the original trackers, used in historical mode
+    Multiplicative,
+}
+
+impl VolumeCurve {
+    pub fn chain(&self, volume: f32, envelope: f32, fadeout: f32) -> f32 {
+        match self {
+            VolumeCurve::Legacy => (fadeout * envelope * volume) / (fadeout + envelope + volume),
+            VolumeCurve::Multiplicative => fadeout * envelope * volume,
+        }
+    }
+}
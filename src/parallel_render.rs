@@ -0,0 +1,101 @@
+/// Offline rendering on several threads, from sequencer checkpoints
+use crate::xmrsplayer::XmrsPlayer;
+use std::thread;
+use std::vec::Vec;
+
+/// Render from current player state until `max_loop_count` is reached (one loop if not set)
+///
+/// A control pass plays the sequencer without computing samples and records the player state
+/// at pattern boundaries, then segments are rendered on `threads` threads from these checkpoints.
+/// Output is the same as calling `sample(true)` on the player, a paused player is rendered as if playing.
+///
+/// The player is not changed. Returns None if rows come from a pattern source given to
+/// `set_pattern_source()`. MIDI sink, scope and metering are not used.
+pub fn render_parallel(player: &XmrsPlayer, threads: usize) -> Option<Vec<(f32, f32)>> {
+    let mut control = player.clone_state()?;
+    control.pause = false;
+    if control.max_loop_count == 0 {
+        control.set_max_loop_count(1);
+    }
+
+    // At least one second between checkpoints
+    let min_segment = control.get_sample_rate() as usize;
+    let mut checkpoints: Vec<(usize, XmrsPlayer)> = vec![(0, control.clone_state()?)];
+    let mut length = 0;
+    let mut played_rows = control.played_rows;
+    loop {
+        // State before next sample
+        if control.played_rows != played_rows {
+            played_rows = control.played_rows;
+            let last = checkpoints.last().map_or(0, |c| c.0);
+            if control.played_row.1 == 0 && length - last >= min_segment {
+                checkpoints.push((length, control.clone_state()?));
+            }
+        }
+        if !control.skip_sample() {
+            break;
+        }
+        length += 1;
+    }
+
+    // Keep `threads` checkpoints, evenly spaced
+    let threads = threads.clamp(1, checkpoints.len());
+    let mut segments: Vec<(usize, XmrsPlayer)> = Vec::with_capacity(threads);
+    for (offset, state) in checkpoints {
+        let target = segments.len() * length / threads;
+        if segments.len() < threads && offset >= target {
+            segments.push((offset, state));
+        }
+    }
+
+    let mut ends: Vec<usize> = segments.iter().skip(1).map(|s| s.0).collect();
+    ends.push(length);
+
+    let buffers: Vec<Vec<(f32, f32)>> = thread::scope(|scope| {
+        let workers: Vec<_> = segments
+            .into_iter()
+            .zip(ends)
+            .map(|((start, mut state), end)| {
+                scope.spawn(move || {
+                    let mut buffer = Vec::with_capacity(end - start);
+                    for _ in start..end {
+                        match state.sample(true) {
+                            Some(sample) => buffer.push(sample),
+                            None => break,
+                        }
+                    }
+                    buffer
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+
+    Some(buffers.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::*;
+
+    #[test]
+    fn parallel_output_is_identical() {
+        let module = song(8, 8);
+        let mut player = XmrsPlayer::new(&module, 8000.0, false);
+        player.set_max_loop_count(1);
+        player.pause = true;
+        let parallel = render_parallel(&player, 4).unwrap();
+
+        player.pause = false;
+        let mut sequential = vec![];
+        while let Some(sample) = player.sample(true) {
+            sequential.push(sample);
+        }
+        assert_eq!(parallel.len(), sequential.len());
+        assert!(parallel
+            .iter()
+            .zip(&sequential)
+            .all(|(a, b)| a.0.to_bits() == b.0.to_bits() && a.1.to_bits() == b.1.to_bits()));
+    }
+}
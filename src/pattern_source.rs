@@ -0,0 +1,77 @@
+/// Where the sequencer reads its rows from
+use crate::edit_session::EditCommand;
+use xmrs::prelude::*;
+
+/// Rows given to the sequencer
+///
+/// A position is an index in a song order list, each position has its own number of rows.
+/// Effects like Bxx, Dxx or E6y use these positions and rows.
+pub trait PatternSource {
+    /// Number of positions in the order list
+    fn song_length(&self) -> usize;
+
+    /// Position used when the song loops
+    fn restart_position(&self) -> usize {
+        0
+    }
+
+    /// Pattern number at this position, None if unknown or invalid
+    fn pattern_at(&self, _position: usize) -> Option<usize> {
+        None
+    }
+
+    /// Number of rows at this position, 0 if the position is invalid
+    fn num_rows(&mut self, position: usize) -> usize;
+
+    /// Fill `slots` with the row, one slot for each channel
+    fn get_row(&mut self, position: usize, row: usize, slots: &mut [PatternSlot]);
+
+    /// Apply an edit command between two ticks, returns false if the source is not editable
+    fn edit(&mut self, _command: &EditCommand) -> bool {
+        false
+    }
+}
+
+/// Module order list and patterns, default `XmrsPlayer` source
+pub struct ModuleSource<'a> {
+    module: &'a Module,
+}
+
+impl<'a> ModuleSource<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        Self { module }
+    }
+
+    fn pattern(&self, position: usize) -> Option<&'a Pattern> {
+        let module: &'a Module = self.module;
+        module
+            .pattern_order
+            .get(position)
+            .and_then(|&p| module.pattern.get(p))
+    }
+}
+
+impl<'a> PatternSource for ModuleSource<'a> {
+    fn song_length(&self) -> usize {
+        self.module.get_song_length()
+    }
+
+    fn restart_position(&self) -> usize {
+        self.module.restart_position
+    }
+
+    fn pattern_at(&self, position: usize) -> Option<usize> {
+        self.module.pattern_order.get(position).copied()
+    }
+
+    fn num_rows(&mut self, position: usize) -> usize {
+        self.pattern(position).map_or(0, |p| p.len())
+    }
+
+    fn get_row(&mut self, position: usize, row: usize, slots: &mut [PatternSlot]) {
+        let row = self.pattern(position).and_then(|p| p.get(row));
+        for (i, slot) in slots.iter_mut().enumerate() {
+            *slot = row.and_then(|r| r.get(i)).copied().unwrap_or_default();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+/// Channel storage of `XmrsPlayer`, inline or on the heap
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+
+/// One value for each channel, used as a slice
+///
+/// With `N` > 0, `N` values are kept inline, whatever the number of module channels.
+/// With `N` = 0, values are on the heap, one for each module channel.
+#[derive(Clone, Debug)]
+pub struct PerChannel<T, const N: usize> {
+    fixed: [T; N],
+    heap: Vec<T>,
+}
+
+impl<T, const N: usize> PerChannel<T, N> {
+    /// `N` values, or `len` values if `N` is 0
+    pub fn new(len: usize, mut value: impl FnMut() -> T) -> Self {
+        let fixed = core::array::from_fn(|_| value());
+        let heap = if N == 0 {
+            (0..len).map(|_| value()).collect()
+        } else {
+            Vec::new()
+        };
+        Self { fixed, heap }
+    }
+}
+
+impl<T: Default, const N: usize> Default for PerChannel<T, N> {
+    fn default() -> Self {
+        Self::new(0, T::default)
+    }
+}
+
+impl<T, const N: usize> Deref for PerChannel<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        if N == 0 {
+            &self.heap
+        } else {
+            &self.fixed
+        }
+    }
+}
+
+impl<T, const N: usize> DerefMut for PerChannel<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        if N == 0 {
+            &mut self.heap
+        } else {
+            &mut self.fixed
+        }
+    }
+}
+
+impl<'p, T, const N: usize> IntoIterator for &'p PerChannel<T, N> {
+    type Item = &'p T;
+    type IntoIter = core::slice::Iter<'p, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'p, T, const N: usize> IntoIterator for &'p mut PerChannel<T, N> {
+    type Item = &'p mut T;
+    type IntoIter = core::slice::IterMut<'p, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
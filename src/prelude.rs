@@ -6,3 +6,6 @@
 /// ```
 ///
 pub use crate::xmrsplayer::XmrsPlayer;
+
+pub use crate::channel::{ChannelDefault, ChannelInfo};
+pub use crate::instrument_sampler::InstrumentSampler;
@@ -0,0 +1,204 @@
+/// Compact count of played rows, to detect song loops
+use alloc::{vec, vec::Vec};
+use xmrs::prelude::MAX_NUM_ROWS;
+
+/// Number of times each (song position, row) was played, up to 255
+///
+/// One byte is kept for each row of each position, so memory depends on pattern lengths and not
+/// on song length × `MAX_NUM_ROWS`. Once rows are reserved with `reserve()`, counting doesn't allocate.
+#[derive(Clone, Debug, Default)]
+pub struct RowVisits {
+    /// Visits for each song position, grown up to the last row played
+    counts: Vec<Vec<u8>>,
+}
+
+impl RowVisits {
+    /// Visits are counted up to this value
+    pub const MAX_VISITS: usize = u8::MAX as usize;
+
+    pub fn new(song_length: usize) -> Self {
+        Self {
+            counts: vec![vec![]; song_length],
+        }
+    }
+
+    /// Room for `rows` rows at `position`
+    pub fn reserve(&mut self, position: usize, rows: usize) {
+        if let Some(counts) = self.counts.get_mut(position) {
+            counts.reserve(rows.min(MAX_NUM_ROWS).saturating_sub(counts.len()));
+        }
+    }
+
+    /// Forget positions from `song_length`
+    pub fn resize(&mut self, song_length: usize) {
+        self.counts.resize(song_length, vec![]);
+    }
+
+    pub fn clear(&mut self) {
+        for counts in &mut self.counts {
+            counts.clear();
+        }
+    }
+
+    /// Number of times a row was played, 255 at most
+    pub fn visits(&self, position: usize, row: usize) -> usize {
+        self.counts
+            .get(position)
+            .and_then(|counts| counts.get(row))
+            .map_or(0, |&count| count as usize)
+    }
+
+    /// Count a new visit and return the number of previous ones
+    ///
+    /// Returns None and counts nothing if the position is after song length or the row after `MAX_NUM_ROWS`.
+    pub fn visit(&mut self, position: usize, row: usize) -> Option<usize> {
+        if row >= MAX_NUM_ROWS || position >= self.counts.len() {
+            return None;
+        }
+        let counts = &mut self.counts[position];
+        if counts.len() <= row {
+            counts.resize(row + 1, 0);
+        }
+        let previous = counts[row];
+        counts[row] = previous.saturating_add(1);
+        Some(previous as usize)
+    }
+
+    /// Approximate heap size in bytes
+    pub fn memory_size(&self) -> usize {
+        self.counts.capacity() * core::mem::size_of::<Vec<u8>>()
+            + self.counts.iter().map(|c| c.capacity()).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overview::song_duration;
+    use crate::test_module::*;
+    use crate::xmrsplayer::XmrsPlayer;
+    use xmrs::prelude::*;
+
+    /// Rows played until the song loops `loops` times, a row lasts 240 samples at 2000 Hz
+    fn rows_played(module: &Module, loops: usize) -> u64 {
+        let duration = song_duration(module, 2000.0, false, loops);
+        assert_eq!(duration % 240, 0);
+        duration / 240
+    }
+
+    /// Rows played before the song comes back to its first row, without loop detection
+    fn rows_to_restart(module: &Module) -> u64 {
+        let mut player = XmrsPlayer::new(module, 2000.0, false);
+        let mut rows = 0;
+        while rows < 10000 {
+            player.step();
+            if player.played_rows != rows {
+                if rows > 0 && player.played_row == (0, 0) {
+                    return rows;
+                }
+                rows = player.played_rows;
+            }
+        }
+        panic!("the song doesn't come back to its first row");
+    }
+
+    fn song(patterns: Vec<Pattern>) -> Module {
+        let order = (0..patterns.len()).collect();
+        module(vec![], patterns, order)
+    }
+
+    #[test]
+    fn counts_visits() {
+        let mut visits = RowVisits::new(2);
+        assert_eq!(visits.visit(1, 70), Some(0));
+        assert_eq!(visits.visit(1, 70), Some(1));
+        assert_eq!(visits.visits(1, 70), 2);
+        assert_eq!(visits.visits(1, 69), 0);
+        assert_eq!(visits.visit(2, 0), None);
+        assert_eq!(visits.visit(0, MAX_NUM_ROWS), None);
+
+        let size = visits.memory_size();
+        for _ in 0..300 {
+            visits.visit(1, 70);
+        }
+        assert_eq!(visits.visits(1, 70), RowVisits::MAX_VISITS);
+        assert_eq!(visits.memory_size(), size);
+
+        visits.resize(1);
+        assert_eq!(visits.visit(1, 70), None);
+        visits.clear();
+        assert_eq!(visits.visit(0, 0), Some(0));
+    }
+
+    #[test]
+    fn restart_position() {
+        let mut module = song(vec![pattern(16, 1); 4]);
+        module.restart_position = 2;
+        assert_eq!(rows_played(&module, 1), 64);
+        assert_eq!(rows_played(&module, 2), 64 + 32);
+    }
+
+    #[test]
+    fn pattern_loop() {
+        // Rows 2 to 5 are played 3 times
+        let mut p = pattern(16, 2);
+        p[2][0] = effect(0xE, 0x60);
+        p[5][0] = effect(0xE, 0x62);
+        assert_eq!(rows_played(&song(vec![p.clone()]), 1), 2 + 4 * 3 + 10);
+
+        // A loop of the other channel around the first one: rows 2 to 5 are played 3 times, twice
+        let mut nested = p.clone();
+        nested[1][1] = effect(0xE, 0x60);
+        nested[7][1] = effect(0xE, 0x61);
+        let module = song(vec![p, nested]);
+        assert_eq!(rows_played(&module, 1), rows_to_restart(&module));
+        assert_eq!(rows_played(&module, 1), 24 + 1 + (1 + 4 * 3 + 2) * 2 + 8);
+    }
+
+    #[test]
+    fn jumps() {
+        // Position 0 breaks to row 8 of position 1, position 2 jumps back to position 1:
+        // the loop starts at row 0 of position 1, not played before
+        let mut patterns = vec![pattern(16, 2); 3];
+        patterns[0][4][0] = effect(0xD, 0x08);
+        patterns[2][8][0] = effect(0xB, 0x01);
+        assert_eq!(rows_played(&song(patterns.clone()), 1), 5 + 8 + 9 + 8);
+
+        // Bxx and Dxx on the same row: jump to row 4 of position 0
+        patterns[2][8][0] = effect(0xB, 0x00);
+        patterns[2][8][1] = effect(0xD, 0x04);
+        assert_eq!(rows_played(&song(patterns.clone()), 1), 5 + 8 + 9);
+        assert_eq!(rows_played(&song(patterns), 2), 5 + 8 + 9 + 1 + 8 + 9);
+    }
+
+    #[test]
+    fn max_loop_count_is_clamped() {
+        let module = song(vec![pattern(1, 1)]);
+        let mut player = XmrsPlayer::new(&module, 2000.0, false);
+        player.set_max_loop_count(300);
+        assert_eq!(player.max_loop_count, RowVisits::MAX_VISITS);
+        let mut samples = 0;
+        while !player.is_finished() {
+            player.step();
+            samples += 1;
+            assert!(samples <= 256 * 240, "still playing");
+        }
+        assert_eq!(player.row_loop_count(0, 0), RowVisits::MAX_VISITS);
+
+        // Set without the setter
+        let mut player = XmrsPlayer::new(&module, 2000.0, false);
+        player.max_loop_count = 1000;
+        for _ in 0..256 * 240 {
+            player.step();
+        }
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn pattern_delay() {
+        // Row 3 lasts 3 rows
+        let mut p = pattern(16, 1);
+        p[3][0] = effect(0xE, 0xE2);
+        assert_eq!(rows_played(&song(vec![p]), 1), 18);
+    }
+}
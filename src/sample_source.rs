@@ -0,0 +1,465 @@
+/// Where sample frames are read from: module memory, flash, disk...
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::{Deref, Range};
+use xmrs::instr_default::InstrDefault;
+use xmrs::sample::{Sample, SampleDataType};
+
+/// Random access to the frames of a sample
+///
+/// Loop points, volume and panning still come from the module `Sample`, only frames are read here.
+/// Sources are shared between channels, so a cache needs interior mutability.
+pub trait SampleSource: Sync {
+    /// Number of frames
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// (left, right) frame between -1.0 and 1.0, `index` is lower than `len()`
+    fn at(&self, index: usize) -> (f32, f32);
+
+    /// (left, right) frame as Q15 integers
+    fn at_q15(&self, index: usize) -> (i32, i32) {
+        let (l, r) = self.at(index);
+        ((l * 32768.0) as i32, (r * 32768.0) as i32)
+    }
+
+    /// Frames are decoded by blocks of this length when not 0, see `decode_block()`
+    fn block_len(&self) -> usize {
+        0
+    }
+
+    /// Replace `out` with the Q15 frames of `block`, `block_len()` frames except for the last block
+    fn decode_block(&self, _block: usize, _out: &mut Vec<(i16, i16)>) {}
+
+    /// Frames in memory, read directly by the span mixer
+    fn data(&self) -> Option<&SampleDataType> {
+        None
+    }
+
+    /// Frames that will be played soon, from the current position and from the loop start
+    ///
+    /// Called once per tick for each playing voice, on the render thread: slow reads should be left
+    /// to another thread. Ranges are in playing order.
+    fn prefetch(&self, _ranges: &[Range<usize>]) {}
+}
+
+/// Frames decoded in memory, as loaded by xmrs
+impl SampleSource for Sample {
+    fn len(&self) -> usize {
+        Sample::len(self)
+    }
+
+    fn at(&self, index: usize) -> (f32, f32) {
+        Sample::at(self, index)
+    }
+
+    fn data(&self) -> Option<&SampleDataType> {
+        Some(&self.data)
+    }
+
+    fn at_q15(&self, index: usize) -> (i32, i32) {
+        match &self.data {
+            SampleDataType::Mono8(v) => (v[index] as i32 * 256, v[index] as i32 * 256),
+            SampleDataType::Mono16(v) => (v[index] as i32, v[index] as i32),
+            SampleDataType::Stereo8(v) => {
+                (v[index * 2] as i32 * 256, v[index * 2 + 1] as i32 * 256)
+            }
+            SampleDataType::Stereo16(v) => (v[index * 2] as i32, v[index * 2 + 1] as i32),
+        }
+    }
+}
+
+/// Source borrowed for the player lifetime, or shared with data created while playing
+#[derive(Clone)]
+pub enum SourceRef<'a> {
+    Borrowed(&'a dyn SampleSource),
+    Shared(Arc<dyn SampleSource + Send>),
+    /// Sample of an instrument created while playing, by index in `sample`
+    Instrument(Arc<InstrDefault>, usize),
+}
+
+impl<'a> Deref for SourceRef<'a> {
+    type Target = dyn SampleSource + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            SourceRef::Borrowed(source) => *source,
+            SourceRef::Shared(source) => source.as_ref(),
+            SourceRef::Instrument(instr, index) => &instr.sample[*index],
+        }
+    }
+}
+
+/// Signed PCM frame format of a `FileSampleSource`, 16 bits values are little endian
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    Mono8,
+    Mono16,
+    Stereo8,
+    Stereo16,
+}
+
+impl SampleFormat {
+    pub fn of(sample: &Sample) -> Self {
+        match sample.data {
+            SampleDataType::Mono8(_) => SampleFormat::Mono8,
+            SampleDataType::Mono16(_) => SampleFormat::Mono16,
+            SampleDataType::Stereo8(_) => SampleFormat::Stereo8,
+            SampleDataType::Stereo16(_) => SampleFormat::Stereo16,
+        }
+    }
+
+    pub fn frame_size(&self) -> usize {
+        match self {
+            SampleFormat::Mono8 => 1,
+            SampleFormat::Mono16 | SampleFormat::Stereo8 => 2,
+            SampleFormat::Stereo16 => 4,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn decode(&self, frame: &[u8]) -> (f32, f32) {
+        let s8 = |i: usize| frame[i] as i8 as f32 / 128.0;
+        let s16 = |i: usize| i16::from_le_bytes([frame[i], frame[i + 1]]) as f32 / 32768.0;
+        match self {
+            SampleFormat::Mono8 => (s8(0), s8(0)),
+            SampleFormat::Mono16 => (s16(0), s16(0)),
+            SampleFormat::Stereo8 => (s8(0), s8(1)),
+            SampleFormat::Stereo16 => (s16(0), s16(2)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use file::{write_pcm, FileSampleSource};
+
+#[cfg(feature = "std")]
+mod file {
+    use super::{SampleFormat, SampleSource};
+    use core::ops::Range;
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread::JoinHandle;
+    use std::vec::Vec;
+    use xmrs::sample::{Sample, SampleDataType};
+
+    /// Frames read from `window` frames long windows
+    struct Window {
+        start: usize,
+        data: Vec<u8>,
+    }
+
+    impl Window {
+        fn contains(&self, index: usize, format: SampleFormat) -> bool {
+            index >= self.start && index < self.start + self.data.len() / format.frame_size()
+        }
+    }
+
+    /// Two windows, most recently used first: playing position and loop start
+    type Windows = [Option<Window>; 2];
+
+    fn find(windows: &Windows, index: usize, format: SampleFormat) -> Option<usize> {
+        windows
+            .iter()
+            .position(|w| w.as_ref().is_some_and(|w| w.contains(index, format)))
+    }
+
+    /// Put `window` first, returns the evicted window
+    fn insert(windows: &mut Windows, window: Window) -> Option<Window> {
+        let evicted = windows[1].take();
+        windows.swap(0, 1);
+        windows[0] = Some(window);
+        evicted
+    }
+
+    struct Reader {
+        file: File,
+        offset: u64,
+        len: usize,
+        format: SampleFormat,
+        window: usize,
+    }
+
+    impl Reader {
+        /// Window from the start of `frames`, with some frames before it too, for ping-pong loops
+        fn read(&mut self, frames: Range<usize>) -> Option<Window> {
+            let start = frames
+                .start
+                .saturating_sub(self.window / 4)
+                .min(self.len.saturating_sub(self.window));
+            let frames = self.window.min(self.len - start);
+            let mut data = vec![0; frames * self.format.frame_size()];
+            let position = self.offset + (start * self.format.frame_size()) as u64;
+            self.file.seek(SeekFrom::Start(position)).ok()?;
+            self.file.read_exact(&mut data).ok()?;
+            Some(Window { start, data })
+        }
+    }
+
+    struct Cache {
+        windows: Windows,
+        /// Reads on the render thread, None with a background loader
+        reader: Option<Reader>,
+    }
+
+    /// Frames asked to the loader
+    struct Requests {
+        frames: Vec<Range<usize>>,
+        stop: bool,
+    }
+
+    /// Requests queued at most, the render thread doesn't allocate
+    const MAX_REQUESTS: usize = 8;
+
+    struct Shared {
+        cache: Mutex<Cache>,
+        requests: Mutex<Requests>,
+        wake: Condvar,
+        misses: AtomicUsize,
+    }
+
+    /// Sample frames read from a file, with a cache of two windows
+    ///
+    /// While playing, one window follows the current position and the other one keeps the loop start.
+    /// A read error gives silent frames.
+    ///
+    /// With `open()`, a loader thread reads the file: `prefetch` only queues reads, and the render
+    /// thread never waits for the file or for the loader. A frame outside the loaded windows is silent
+    /// and counted in `misses()`. Windows should hold several ticks of frames.
+    /// With `open_blocking()`, a missing window is read on the render thread, for offline rendering.
+    pub struct FileSampleSource {
+        shared: Arc<Shared>,
+        loader: Option<JoinHandle<()>>,
+        len: usize,
+        format: SampleFormat,
+    }
+
+    impl FileSampleSource {
+        /// `len` frames in `format` from byte `offset` of `path`, read by `window` frames in a loader thread
+        pub fn open<P: AsRef<Path>>(
+            path: P,
+            offset: u64,
+            len: usize,
+            format: SampleFormat,
+            window: usize,
+        ) -> io::Result<Self> {
+            let reader = Self::reader(path, offset, len, format, window)?;
+            let mut source = Self::new(None, len, format);
+            let shared = Arc::clone(&source.shared);
+            source.loader = Some(std::thread::spawn(move || Self::load(&shared, reader)));
+            Ok(source)
+        }
+
+        /// Same as `open()`, missing windows are read on the render thread
+        pub fn open_blocking<P: AsRef<Path>>(
+            path: P,
+            offset: u64,
+            len: usize,
+            format: SampleFormat,
+            window: usize,
+        ) -> io::Result<Self> {
+            let reader = Self::reader(path, offset, len, format, window)?;
+            Ok(Self::new(Some(reader), len, format))
+        }
+
+        fn reader<P: AsRef<Path>>(
+            path: P,
+            offset: u64,
+            len: usize,
+            format: SampleFormat,
+            window: usize,
+        ) -> io::Result<Reader> {
+            Ok(Reader {
+                file: File::open(path)?,
+                offset,
+                len,
+                format,
+                window: window.max(2),
+            })
+        }
+
+        fn new(reader: Option<Reader>, len: usize, format: SampleFormat) -> Self {
+            Self {
+                shared: Arc::new(Shared {
+                    cache: Mutex::new(Cache {
+                        windows: [None, None],
+                        reader,
+                    }),
+                    requests: Mutex::new(Requests {
+                        frames: Vec::with_capacity(MAX_REQUESTS),
+                        stop: false,
+                    }),
+                    wake: Condvar::new(),
+                    misses: AtomicUsize::new(0),
+                }),
+                loader: None,
+                len,
+                format,
+            }
+        }
+
+        /// Loader thread: read requested windows until the source is dropped
+        fn load(shared: &Shared, mut reader: Reader) {
+            loop {
+                let frames = {
+                    let Ok(mut requests) = shared.requests.lock() else {
+                        return;
+                    };
+                    while requests.frames.is_empty() && !requests.stop {
+                        requests = match shared.wake.wait(requests) {
+                            Ok(r) => r,
+                            Err(_) => return,
+                        };
+                    }
+                    if requests.stop {
+                        return;
+                    }
+                    requests.frames.remove(0)
+                };
+                if let Ok(mut cache) = shared.cache.lock() {
+                    let last = frames.end.max(frames.start + 1) - 1;
+                    if find(&cache.windows, last, reader.format).is_some() {
+                        match find(&cache.windows, frames.start, reader.format) {
+                            Some(0) => continue,
+                            Some(_) => {
+                                cache.windows.swap(0, 1);
+                                continue;
+                            }
+                            None => {}
+                        }
+                    }
+                }
+                let Some(window) = reader.read(frames) else {
+                    continue;
+                };
+                let evicted = match shared.cache.lock() {
+                    Ok(mut cache) => insert(&mut cache.windows, window),
+                    Err(_) => return,
+                };
+                drop(evicted);
+            }
+        }
+
+        /// Ask the loader for a window with `frames`, dropped if the queue is full or busy
+        fn request(&self, frames: Range<usize>) {
+            if let Ok(mut requests) = self.shared.requests.try_lock() {
+                if !requests.frames.contains(&frames) && requests.frames.len() < MAX_REQUESTS {
+                    requests.frames.push(frames);
+                    self.shared.wake.notify_one();
+                }
+            }
+        }
+
+        /// Frames played as silence because their window was not loaded yet
+        pub fn misses(&self) -> usize {
+            self.shared.misses.load(Ordering::Relaxed)
+        }
+
+        /// True if frame `index` is in a loaded window
+        pub fn is_loaded(&self, index: usize) -> bool {
+            self.shared
+                .cache
+                .try_lock()
+                .is_ok_and(|cache| find(&cache.windows, index, self.format).is_some())
+        }
+
+        fn miss(&self) -> (f32, f32) {
+            self.shared.misses.fetch_add(1, Ordering::Relaxed);
+            (0.0, 0.0)
+        }
+    }
+
+    impl Drop for FileSampleSource {
+        fn drop(&mut self) {
+            if let Some(loader) = self.loader.take() {
+                if let Ok(mut requests) = self.shared.requests.lock() {
+                    requests.stop = true;
+                }
+                self.shared.wake.notify_one();
+                let _ = loader.join();
+            }
+        }
+    }
+
+    impl SampleSource for FileSampleSource {
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        fn at(&self, index: usize) -> (f32, f32) {
+            let size = self.format.frame_size();
+            let cache = match self.loader {
+                Some(_) => self.shared.cache.try_lock(),
+                None => self
+                    .shared
+                    .cache
+                    .lock()
+                    .map_err(|_| std::sync::TryLockError::WouldBlock),
+            };
+            let Ok(mut cache) = cache else {
+                return self.miss();
+            };
+            match find(&cache.windows, index, self.format) {
+                Some(0) => {}
+                Some(_) => cache.windows.swap(0, 1),
+                None => match cache.reader.as_mut() {
+                    Some(reader) => match reader.read(index..index + 1) {
+                        Some(window) => {
+                            insert(&mut cache.windows, window);
+                        }
+                        None => return (0.0, 0.0),
+                    },
+                    None => {
+                        drop(cache);
+                        self.request(index..index + 1);
+                        return self.miss();
+                    }
+                },
+            }
+            match &cache.windows[0] {
+                Some(w) => {
+                    let from = (index - w.start) * size;
+                    self.format.decode(&w.data[from..from + size])
+                }
+                None => (0.0, 0.0),
+            }
+        }
+
+        fn prefetch(&self, ranges: &[Range<usize>]) {
+            // Later ranges first, the current position stays the most recently used
+            for range in ranges.iter().rev().filter(|r| r.start < self.len) {
+                match self.loader {
+                    Some(_) => {
+                        let frames = range.start..range.end.clamp(range.start + 1, self.len);
+                        if !self.is_loaded(frames.start) || !self.is_loaded(frames.end - 1) {
+                            self.request(frames);
+                        }
+                    }
+                    None => {
+                        self.at(range.start);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Write sample frames for a `FileSampleSource` and return their format
+    pub fn write_pcm<W: Write>(sample: &Sample, writer: &mut W) -> io::Result<SampleFormat> {
+        match &sample.data {
+            SampleDataType::Mono8(v) | SampleDataType::Stereo8(v) => {
+                let bytes: Vec<u8> = v.iter().map(|&s| s as u8).collect();
+                writer.write_all(&bytes)?;
+            }
+            SampleDataType::Mono16(v) | SampleDataType::Stereo16(v) => {
+                let bytes: Vec<u8> = v.iter().flat_map(|s| s.to_le_bytes()).collect();
+                writer.write_all(&bytes)?;
+            }
+        }
+        Ok(SampleFormat::of(sample))
+    }
+}
@@ -0,0 +1,220 @@
+/// Oscilloscope ring buffers, filled by `XmrsPlayer` when enabled with `set_scope()`
+use alloc::{vec, vec::Vec};
+
+/// How the waveform start is chosen when reading a buffer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScopeTrigger {
+    /// Last samples, no alignment
+    #[default]
+    None,
+    /// Start on a rising zero crossing, searched over one period of the current frequency
+    ZeroCrossing,
+    /// Start on a multiple of the current period
+    Period,
+}
+
+/// Last samples of one channel, (left + right) / 2
+#[derive(Clone)]
+pub struct ScopeBuffer {
+    data: Vec<f32>,
+    /// Samples written since the beginning
+    count: u64,
+    /// Current pitch in Hz, 0.0 if unknown
+    ///
+    /// Samples are supposed to be tuned: played at 8363 Hz, they sound like a C-4.
+    pub frequency: f32,
+    sample_rate: f32,
+}
+
+impl ScopeBuffer {
+    fn new(length: usize, sample_rate: f32) -> Self {
+        Self {
+            data: vec![0.0; length],
+            count: 0,
+            frequency: 0.0,
+            sample_rate,
+        }
+    }
+
+    fn push(&mut self, value: f32) {
+        let len = self.data.len() as u64;
+        self.data[(self.count % len) as usize] = value;
+        self.count += 1;
+    }
+
+    fn get(&self, index: u64) -> f32 {
+        self.data[(index % self.data.len() as u64) as usize]
+    }
+
+    fn copy_from(&mut self, other: &Self) {
+        if self.data.len() == other.data.len() {
+            self.data.copy_from_slice(&other.data);
+        } else {
+            self.data.clone_from(&other.data);
+        }
+        self.count = other.count;
+        self.frequency = other.frequency;
+        self.sample_rate = other.sample_rate;
+    }
+
+    /// Buffer length in samples
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Fill `out` with the waveform, oldest sample first
+    ///
+    /// If `out` is shorter than the buffer, older samples are used to align the start with `trigger`.
+    /// Samples not yet written are 0.0.
+    pub fn read(&self, out: &mut [f32], trigger: ScopeTrigger) {
+        let n = out.len().min(self.data.len()) as u64;
+        let mut start = self.count.saturating_sub(n);
+        // How far back we can go
+        let history = (self.data.len() as u64 - n).min(start);
+        let period = if self.frequency > 0.0 {
+            self.sample_rate / self.frequency
+        } else {
+            0.0
+        };
+
+        match trigger {
+            ScopeTrigger::None => {}
+            ScopeTrigger::ZeroCrossing => {
+                let search = if period >= 1.0 {
+                    history.min(period as u64 + 1)
+                } else {
+                    history
+                };
+                if let Some(offset) = (0..search)
+                    .find(|&o| self.get(start - o - 1) < 0.0 && self.get(start - o) >= 0.0)
+                {
+                    start -= offset;
+                }
+            }
+            ScopeTrigger::Period => {
+                if period >= 1.0 {
+                    let offset = (start as f64 % period as f64) as u64;
+                    if offset <= history {
+                        start -= offset;
+                    }
+                }
+            }
+        }
+
+        for (i, o) in out.iter_mut().enumerate() {
+            let index = start + i as u64;
+            *o = if (i as u64) < n && index < self.count {
+                self.get(index)
+            } else {
+                0.0
+            };
+        }
+    }
+}
+
+/// Scope buffers of each channel and of the master output
+#[derive(Clone)]
+pub struct Scope {
+    /// Song channels, followed by sound effect voices
+    pub channel: Vec<ScopeBuffer>,
+    /// Output after global volume and amplification
+    pub master: ScopeBuffer,
+}
+
+impl Scope {
+    pub fn new(num_channels: usize, length: usize, sample_rate: f32) -> Self {
+        let length = length.max(1);
+        Self {
+            channel: vec![ScopeBuffer::new(length, sample_rate); num_channels],
+            master: ScopeBuffer::new(length, sample_rate),
+        }
+    }
+
+    pub(crate) fn push(&mut self, samples: &[(f32, f32)], master: (f32, f32)) {
+        if self.channel.len() != samples.len() {
+            let buffer = ScopeBuffer::new(self.master.len(), self.master.sample_rate);
+            self.channel.resize(samples.len(), buffer);
+        }
+        for (buffer, (left, right)) in self.channel.iter_mut().zip(samples) {
+            buffer.push((left + right) * 0.5);
+        }
+        self.master.push((master.0 + master.1) * 0.5);
+    }
+
+    /// Copy without reallocating, to publish to another thread
+    ///
+    /// Buffers are only allocated when the number of channels or the length changes.
+    pub fn copy_from(&mut self, other: &Self) {
+        if self.channel.len() != other.channel.len() {
+            let buffer = ScopeBuffer::new(other.master.len(), other.master.sample_rate);
+            self.channel.resize(other.channel.len(), buffer);
+        }
+        for (buffer, o) in self.channel.iter_mut().zip(&other.channel) {
+            buffer.copy_from(o);
+        }
+        self.master.copy_from(&other.master);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::*;
+    use crate::xmrsplayer::XmrsPlayer;
+
+    const LENGTH: usize = 512;
+
+    fn last(buffer: &ScopeBuffer) -> Vec<f32> {
+        let mut out = vec![0.0; LENGTH];
+        buffer.read(&mut out, ScopeTrigger::None);
+        out
+    }
+
+    #[test]
+    fn scope_captures_channels_and_output() {
+        let module = song(3, 1);
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        player.set_scope(LENGTH);
+        // Each channel starts a note in the first 4 rows
+        let mut out = vec![(0.0, 0.0); 4 * 6 * 960];
+        assert_eq!(player.render(&mut out), out.len());
+
+        // Same song mixed frame by frame, keeping each channel
+        let mut reference = XmrsPlayer::new(&module, 48000.0, false);
+        let mut channels = vec![vec![]; 3];
+        let mut master = vec![];
+        for _ in 0..out.len() {
+            let samples = reference.samples_from_channels().unwrap();
+            for (c, (left, right)) in channels.iter_mut().zip(&samples) {
+                c.push((left + right) * 0.5);
+            }
+            let (left, right) = reference.samples_apply_volume(&samples);
+            master.push((left + right) * 0.5);
+        }
+
+        let scope = player.get_scope().unwrap();
+        assert_eq!(scope.channel.len(), 3);
+        for (buffer, c) in scope.channel.iter().zip(&channels) {
+            assert_eq!(last(buffer), c[c.len() - LENGTH..]);
+        }
+        assert!(channels.iter().all(|c| c.iter().any(|&v| v != 0.0)));
+        assert_eq!(last(&scope.master), master[master.len() - LENGTH..]);
+        let output: Vec<f32> = out[out.len() - LENGTH..]
+            .iter()
+            .map(|(left, right)| (left + right) * 0.5)
+            .collect();
+        assert_eq!(last(&scope.master), output);
+
+        let mut copy = Scope::new(1, 16, 8000.0);
+        copy.copy_from(scope);
+        assert_eq!(copy.channel.len(), 3);
+        for (c, s) in copy.channel.iter().zip(&scope.channel) {
+            assert_eq!(last(c), last(s));
+        }
+        assert_eq!(last(&copy.master), last(&scope.master));
+    }
+}
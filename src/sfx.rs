@@ -0,0 +1,40 @@
+/// Sound effects played over the song
+use xmrs::prelude::*;
+
+/// Where a sound effect is played
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SfxChannel {
+    /// One of the extra voices reserved with `XmrsPlayer::set_sfx_voices()`
+    Reserved(usize),
+    /// A song channel, given back to the song at its next note
+    Song(usize),
+}
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SfxState {
+    pub priority: u8,
+    /// Trigger order, used to replace the oldest sound effect first
+    pub age: u64,
+    pub effect_type: u8,
+    pub effect_parameter: u8,
+}
+
+impl SfxState {
+    pub fn new(slot: &PatternSlot, priority: u8, age: u64) -> Self {
+        Self {
+            priority,
+            age,
+            effect_type: slot.effect_type,
+            effect_parameter: slot.effect_parameter,
+        }
+    }
+
+    /// Slot used on each new row: effect goes on, without a new note
+    pub fn row_slot(&self) -> PatternSlot {
+        PatternSlot {
+            effect_type: self.effect_type,
+            effect_parameter: self.effect_parameter,
+            ..Default::default()
+        }
+    }
+}
@@ -3,15 +3,15 @@ use xmrs::instr_vibrato::InstrVibrato;
 use xmrs::period_helper::{FrequencyType, PeriodHelper};
 
 #[derive(Clone)]
-pub struct StateAutoVibrato<'a> {
-    vibrato: &'a InstrVibrato,
+pub struct StateAutoVibrato {
+    vibrato: InstrVibrato,
     period_helper: PeriodHelper,
     phase: f32,
     pub current_modulation: f32,
 }
 
-impl<'a> StateAutoVibrato<'a> {
-    pub fn new(vibrato: &'a InstrVibrato, period_helper: PeriodHelper) -> Self {
+impl StateAutoVibrato {
+    pub fn new(vibrato: InstrVibrato, period_helper: PeriodHelper) -> Self {
         let mut sv = Self {
             vibrato,
             period_helper,
@@ -1,10 +1,13 @@
 /// An Instrument Envelope State
 use crate::helper::*;
+use crate::state_instr_default::InstrRef;
 use xmrs::prelude::*;
 
 #[derive(Clone)]
 pub struct StateEnvelope<'a> {
-    env: &'a Envelope,
+    instr: InstrRef<'a>,
+    /// Panning envelope of `instr` instead of volume envelope
+    panning: bool,
     default_value: f32,
     pub value: f32,
     pub counter: usize,
@@ -12,17 +15,26 @@ pub struct StateEnvelope<'a> {
 
 impl<'a> StateEnvelope<'a> {
     // value is volume_envelope_volume=1.0 or volume_envelope_panning=0.5
-    pub fn new(env: &'a Envelope, default_value: f32) -> Self {
+    pub fn new(instr: InstrRef<'a>, panning: bool, default_value: f32) -> Self {
         Self {
-            env,
+            instr,
+            panning,
             default_value,
             value: default_value,
             counter: 0,
         }
     }
 
+    fn env(instr: &InstrDefault, panning: bool) -> &Envelope {
+        if panning {
+            &instr.panning_envelope
+        } else {
+            &instr.volume_envelope
+        }
+    }
+
     pub fn has_volume_envelope(&self) -> bool {
-        self.env.enabled
+        Self::env(&self.instr, self.panning).enabled
     }
 
     pub fn reset(&mut self) {
@@ -31,7 +43,8 @@ impl<'a> StateEnvelope<'a> {
     }
 
     pub fn tick(&mut self, sustained: bool) {
-        let num_points = self.env.point.len();
+        let env = Self::env(&self.instr, self.panning);
+        let num_points = env.point.len();
 
         if num_points == 0 {
             self.value = 0.0;
@@ -39,22 +52,22 @@ impl<'a> StateEnvelope<'a> {
         }
 
         if num_points == 1 {
-            self.value = self.env.point[0].value;
+            self.value = env.point[0].value;
             clamp_up(&mut self.value);
             return;
         }
 
-        if self.env.loop_enabled {
-            let loop_start = self.env.point[self.env.loop_start_point as usize].frame;
-            let loop_end = self.env.point[self.env.loop_end_point as usize].frame;
+        if env.loop_enabled {
+            let loop_start = env.point[env.loop_start_point as usize].frame;
+            let loop_end = env.point[env.loop_end_point as usize].frame;
             if self.counter >= loop_end {
                 self.counter -= loop_end - loop_start;
             }
         }
 
         for i in 1..num_points {
-            let prev_point = &self.env.point[i - 1];
-            let curr_point = &self.env.point[i];
+            let prev_point = &env.point[i - 1];
+            let curr_point = &env.point[i];
 
             if self.counter == prev_point.frame {
                 self.value = prev_point.value;
@@ -74,8 +87,8 @@ impl<'a> StateEnvelope<'a> {
 
         /* Make sure it is safe to increment frame count */
         if !sustained
-            || !self.env.sustain_enabled
-            || self.counter != self.env.point[self.env.sustain_point as usize].frame
+            || !env.sustain_enabled
+            || self.counter != env.point[env.sustain_point as usize].frame
         {
             self.counter += 1;
         }
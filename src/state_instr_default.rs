@@ -5,15 +5,36 @@ use micromath::F32Ext;
 #[allow(unused_imports)]
 use num_traits::float::Float;
 
+use alloc::{sync::Arc, vec, vec::Vec};
 use core::ops::Deref;
 
 /// An InstrDefault State
 use crate::helper::*;
+use crate::pan_law::VolumeCurve;
+use crate::sample_source::SourceRef;
 use crate::{
     state_auto_vibrato::StateAutoVibrato, state_envelope::StateEnvelope, state_sample::StateSample,
 };
 use xmrs::prelude::*;
 
+/// Instrument borrowed for the player lifetime, or shared with data created while playing
+#[derive(Clone)]
+pub enum InstrRef<'a> {
+    Borrowed(&'a InstrDefault),
+    Shared(Arc<InstrDefault>),
+}
+
+impl<'a> Deref for InstrRef<'a> {
+    type Target = InstrDefault;
+
+    fn deref(&self) -> &InstrDefault {
+        match self {
+            InstrRef::Borrowed(instr) => instr,
+            InstrRef::Shared(instr) => instr,
+        }
+    }
+}
+
 impl<'a> Deref for StateInstrDefault<'a> {
     type Target = InstrDefault;
     fn deref(&self) -> &InstrDefault {
@@ -23,15 +44,23 @@ impl<'a> Deref for StateInstrDefault<'a> {
 
 #[derive(Clone)]
 pub struct StateInstrDefault<'a> {
-    instr: &'a InstrDefault,
+    instr: InstrRef<'a>,
     pub num: usize,
     /// Output frequency
     rate: f32,
     period_helper: PeriodHelper,
     /// Sample state
     pub state_sample: Option<StateSample<'a>>,
+    /// Index of the sample in `instr.sample`
+    pub sample_num: usize,
+    /// Block buffers kept without a sample state, see `StateSample::with_block_buffers()`
+    block_buffers: [Vec<(i16, i16)>; 2],
+    /// Nearest neighbour instead of linear interpolation
+    pub nearest: bool,
+    /// Frames read from another source than the module, by sample index
+    pub sample_source: Vec<(usize, SourceRef<'a>)>,
     /// Vibrato state
-    pub state_vibrato: StateAutoVibrato<'a>,
+    pub state_vibrato: StateAutoVibrato,
     /// Volume Envelope state
     pub envelope_volume: StateEnvelope<'a>,
     /// Panning Envelope state
@@ -52,24 +81,23 @@ pub struct StateInstrDefault<'a> {
 }
 
 impl<'a> StateInstrDefault<'a> {
-    pub fn new(
-        instr: &'a InstrDefault,
-        num: usize,
-        period_helper: PeriodHelper,
-        rate: f32,
-    ) -> Self {
-        let v = &instr.vibrato;
-        let ve = &instr.volume_envelope;
-        let pe = &instr.panning_envelope;
+    pub fn new(instr: InstrRef<'a>, num: usize, period_helper: PeriodHelper, rate: f32) -> Self {
+        let v = instr.vibrato;
+        let ve = instr.clone();
+        let pe = instr.clone();
         Self {
             instr,
             num,
             rate,
             period_helper: period_helper.clone(),
             state_sample: None,
+            sample_num: 0,
+            block_buffers: Default::default(),
+            nearest: false,
+            sample_source: vec![],
             state_vibrato: StateAutoVibrato::new(v, period_helper),
-            envelope_volume: StateEnvelope::new(ve, 1.0),
-            envelope_panning: StateEnvelope::new(pe, 0.5),
+            envelope_volume: StateEnvelope::new(ve, false, 1.0),
+            envelope_panning: StateEnvelope::new(pe, true, 0.5),
             sustained: true,
             volume_fadeout: 1.0,
             volume: 1.0,
@@ -82,7 +110,7 @@ impl<'a> StateInstrDefault<'a> {
         self.envelope_volume.has_volume_envelope()
     }
 
-    pub fn replace_instr(&mut self, instr: &'a InstrDefault) {
+    pub fn replace_instr(&mut self, instr: InstrRef<'a>) {
         self.instr = instr;
     }
 
@@ -93,6 +121,11 @@ impl<'a> StateInstrDefault<'a> {
         }
     }
 
+    /// True if the sample was played to its end, see `StateSample::is_ended()`
+    pub fn is_ended(&self) -> bool {
+        self.state_sample.as_ref().is_some_and(|s| s.is_ended())
+    }
+
     pub fn sample_reset(&mut self) {
         match &mut self.state_sample {
             Some(s) => s.reset(),
@@ -132,9 +165,8 @@ impl<'a> StateInstrDefault<'a> {
         }
     }
 
-    pub fn get_volume(&self) -> f32 {
-        (self.volume_fadeout * self.envelope_volume.value * self.volume)
-            / (self.volume_fadeout + self.envelope_volume.value + self.volume)
+    pub fn get_volume(&self, curve: VolumeCurve) -> f32 {
+        curve.chain(self.volume, self.envelope_volume.value, self.volume_fadeout)
     }
 
     fn envelopes(&mut self) {
@@ -185,16 +217,39 @@ impl<'a> StateInstrDefault<'a> {
         }
     }
 
+    /// Block buffers of the current sample, to be reused by the next one
+    pub fn take_block_buffers(&mut self) -> [Vec<(i16, i16)>; 2] {
+        match &mut self.state_sample {
+            Some(s) => s.take_block_buffers(),
+            None => core::mem::take(&mut self.block_buffers),
+        }
+    }
+
+    pub fn set_block_buffers(&mut self, buffers: [Vec<(i16, i16)>; 2]) {
+        self.block_buffers = buffers;
+    }
+
     fn select_sample(&mut self, num: usize) -> bool {
+        let buffers = self.take_block_buffers();
         if num < self.instr.sample.len() {
-            let sample = &self.instr.sample[num];
-            let state_sample = StateSample::new(sample, self.rate);
+            let source = match self.sample_source.iter().find(|(i, _)| *i == num) {
+                Some((_, source)) => source.clone(),
+                None => match &self.instr {
+                    InstrRef::Borrowed(instr) => SourceRef::Borrowed(&instr.sample[num]),
+                    InstrRef::Shared(instr) => SourceRef::Instrument(instr.clone(), num),
+                },
+            };
+            let mut state_sample = StateSample::new(&self.instr.sample[num], source, self.rate)
+                .with_block_buffers(buffers);
+            state_sample.nearest = self.nearest;
             self.panning = state_sample.get_panning();
             self.volume = state_sample.get_volume();
             self.volume_orig = self.volume;
             self.state_sample = Some(state_sample);
+            self.sample_num = num;
             return true;
         } else {
+            self.block_buffers = buffers;
             self.state_sample = None;
             self.panning = 0.5;
             self.volume = 0.0;
@@ -202,6 +257,24 @@ impl<'a> StateInstrDefault<'a> {
         }
     }
 
+    /// Same as `next()`, without computing the sample
+    pub fn skip(&mut self) {
+        if self.is_enabled() {
+            if let Some(s) = &mut self.state_sample {
+                s.skip();
+            }
+        }
+    }
+
+    /// Same as `next()` with integer arithmetic, Q15 values
+    #[cfg(feature = "fixed")]
+    pub fn next_q15(&mut self) -> Option<(i32, i32)> {
+        match &mut self.state_sample {
+            Some(s) => s.next_q15(),
+            None => None,
+        }
+    }
+
     pub fn tick(&mut self) {
         self.envelopes();
         self.state_vibrato.tick(self.sustained);
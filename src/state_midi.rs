@@ -0,0 +1,99 @@
+/// A Channel MIDI State
+use crate::midi::{MidiEvent, MidiSink};
+
+#[cfg(feature = "micromath")]
+#[allow(unused_imports)]
+use micromath::F32Ext;
+#[cfg(feature = "libm")]
+#[allow(unused_imports)]
+use num_traits::float::Float;
+
+#[derive(Clone, Default)]
+pub struct StateMidi {
+    /// (midi channel, key) of the sounding note
+    note: Option<(u8, u8)>,
+    /// Note used as pitch-bend center
+    base_note: f32,
+    program: Option<(u8, u8)>,
+    bend: Option<u16>,
+    volume: Option<u8>,
+}
+
+impl StateMidi {
+    pub fn release(&mut self, frame: u64, sink: &mut dyn MidiSink) {
+        if let Some((channel, key)) = self.note.take() {
+            sink.send(frame, MidiEvent::NoteOff { channel, key });
+        }
+    }
+
+    /// note is a tracker note (0 is C-0), velocity from 0.0 to 1.0
+    pub fn trigger(
+        &mut self,
+        frame: u64,
+        sink: &mut dyn MidiSink,
+        channel: u8,
+        program: u8,
+        note: f32,
+        velocity: f32,
+    ) {
+        self.release(frame, sink);
+
+        if self.program != Some((channel, program)) {
+            sink.send(frame, MidiEvent::ProgramChange { channel, program });
+            self.program = Some((channel, program));
+        }
+
+        let key = (note.round() as i32 + 12).clamp(0, 127) as u8;
+        self.base_note = note.round();
+        self.bend = None;
+        let velocity = ((velocity * 127.0) as u8).max(1);
+        sink.send(
+            frame,
+            MidiEvent::NoteOn {
+                channel,
+                key,
+                velocity,
+            },
+        );
+        self.note = Some((channel, key));
+    }
+
+    /// note is the effective tracker note, range is the bend range in semitones
+    pub fn update(
+        &mut self,
+        frame: u64,
+        sink: &mut dyn MidiSink,
+        note: f32,
+        range: f32,
+        volume: f32,
+    ) {
+        let Some((channel, _)) = self.note else {
+            return;
+        };
+
+        let bend = (8192.0 + (note - self.base_note) / range * 8192.0).clamp(0.0, 16383.0) as u16;
+        if self.bend != Some(bend) {
+            sink.send(
+                frame,
+                MidiEvent::PitchBend {
+                    channel,
+                    value: bend,
+                },
+            );
+            self.bend = Some(bend);
+        }
+
+        let volume = (volume.clamp(0.0, 1.0) * 127.0) as u8;
+        if self.volume != Some(volume) {
+            sink.send(
+                frame,
+                MidiEvent::ControlChange {
+                    channel,
+                    controller: MidiEvent::CC_VOLUME,
+                    value: volume,
+                },
+            );
+            self.volume = Some(volume);
+        }
+    }
+}
@@ -1,6 +1,9 @@
 /// A Sample State
 use crate::helper::*;
-use xmrs::sample::Sample;
+use crate::sample_source::SourceRef;
+use alloc::vec::Vec;
+use core::ops::Range;
+use xmrs::sample::{LoopType, Sample, SampleDataType};
 
 #[cfg(feature = "micromath")]
 #[allow(unused_imports)]
@@ -30,7 +33,18 @@ const M: FixedOrFloat = 8; // multiplicator (2^M): Here we choose 8 because 32 -
 
 #[derive(Clone)]
 pub struct StateSample<'a> {
-    sample: &'a Sample,
+    loop_start: usize,
+    loop_length: usize,
+    flags: LoopType,
+    volume: f32,
+    panning: f32,
+    relative_note: i8,
+    /// Frames
+    source: SourceRef<'a>,
+    /// Last decoded blocks of a block source, most recently used first: (block, Q15 frames)
+    blocks: [(usize, Vec<(i16, i16)>); 2],
+    /// `source.block_len()`
+    block_len: usize,
     finetune: f32,
     /// current seek position
     position: FixedOrFloat,
@@ -38,21 +52,52 @@ pub struct StateSample<'a> {
     step: Option<FixedOrFloat>,
     // Output frequency
     rate: f32,
+    /// Playback frequency in Hz
+    frequency: f32,
+    /// Nearest neighbour instead of linear interpolation
+    pub nearest: bool,
 }
 
 impl<'a> StateSample<'a> {
-    pub fn new(sample: &'a Sample, rate: f32) -> Self {
+    /// Loop points, volume, panning and finetune come from `sample`, frames from `source`
+    pub fn new(sample: &Sample, source: SourceRef<'a>, rate: f32) -> Self {
         let position = StateSample::default_position();
         let finetune = sample.finetune;
         Self {
-            sample,
+            loop_start: sample.loop_start as usize,
+            loop_length: sample.loop_length as usize,
+            flags: sample.flags,
+            volume: sample.volume,
+            panning: sample.panning,
+            relative_note: sample.relative_note,
+            block_len: source.block_len(),
+            source,
+            blocks: [(usize::MAX, Vec::new()), (usize::MAX, Vec::new())],
             finetune,
             position,
             step: None,
             rate,
+            frequency: 0.0,
+            nearest: false,
         }
     }
 
+    /// Reuse the block buffers of a previous sample, so that a new note doesn't allocate
+    pub fn with_block_buffers(mut self, buffers: [Vec<(i16, i16)>; 2]) -> Self {
+        for (block, buffer) in self.blocks.iter_mut().zip(buffers) {
+            block.1 = buffer;
+        }
+        self
+    }
+
+    /// Block buffers, for the next sample with `with_block_buffers()`
+    pub fn take_block_buffers(&mut self) -> [Vec<(i16, i16)>; 2] {
+        self.blocks.each_mut().map(|(block, buffer)| {
+            *block = usize::MAX;
+            core::mem::take(buffer)
+        })
+    }
+
     #[inline(always)]
     fn default_position() -> FixedOrFloat {
         #[cfg(feature = "use_f64")]
@@ -71,7 +116,8 @@ impl<'a> StateSample<'a> {
     }
 
     pub fn set_step(&mut self, frequency: f32) {
-        if self.sample.len() == 0 {
+        self.frequency = frequency;
+        if self.source.is_empty() {
             self.disable();
         } else {
             #[cfg(feature = "use_f64")]
@@ -89,35 +135,166 @@ impl<'a> StateSample<'a> {
         self.step.is_some()
     }
 
+    /// True if a sample without loop was played to its end, it then gives silence
+    pub fn is_ended(&self) -> bool {
+        matches!(self.flags, LoopType::No) && self.get_position() as usize >= self.source.len()
+    }
+
     pub fn disable(&mut self) {
         self.step = None;
     }
 
+    /// Playback frequency in Hz, 0.0 if disabled
+    pub fn get_frequency(&self) -> f32 {
+        if self.is_enabled() {
+            self.frequency
+        } else {
+            0.0
+        }
+    }
+
+    /// Current seek position in sample frames
+    pub fn get_playback_position(&self) -> f32 {
+        #[cfg(feature = "use_f64")]
+        {
+            self.position as f32
+        }
+        #[cfg(not(feature = "use_f64"))]
+        {
+            self.position as f32 / (1 << M) as f32
+        }
+    }
+
     pub fn get_panning(&self) -> f32 {
-        self.sample.panning
+        self.panning
     }
 
     pub fn get_volume(&self) -> f32 {
-        self.sample.volume
+        self.volume
     }
 
     /// use sample finetune or force if finetune arg!=0
     pub fn get_finetuned_note(&self) -> f32 {
-        self.sample.relative_note as f32 + self.finetune
+        self.relative_note as f32 + self.finetune
     }
 
     pub fn set_finetune(&mut self, finetune: f32) {
         self.finetune = finetune;
     }
 
+    /// Same as `Sample::meta_seek()` with the length of the source: (position, frame index)
+    fn meta_seek(&self, pos: usize) -> (usize, usize) {
+        let len = self.source.len();
+        let loop_start = self.loop_start;
+        let loop_length = self.loop_length;
+        let loop_end = loop_start + loop_length;
+
+        match self.flags {
+            // The position stops just after the end, so that `is_ended()` can see it
+            LoopType::No => (pos.min(len), pos.min(len - 1)),
+            LoopType::Forward => {
+                let pos = if pos < loop_end {
+                    pos
+                } else {
+                    loop_start + (pos - loop_start) % loop_length
+                };
+                (pos, pos)
+            }
+            LoopType::PingPong => {
+                if pos < loop_end {
+                    (pos, pos)
+                } else {
+                    let mod_pos = (pos - loop_start) % (2 * loop_length);
+                    if mod_pos < loop_length {
+                        (loop_start + mod_pos, loop_start + mod_pos)
+                    } else {
+                        (loop_start + mod_pos, loop_end - (mod_pos - loop_length) - 1)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tell the source which frames the next `frames` output samples will read
+    pub fn prefetch(&self, frames: usize) {
+        let Some(step) = self.step else {
+            return;
+        };
+        #[cfg(feature = "use_f64")]
+        let span = (step * frames as FixedOrFloat) as usize + 2;
+        #[cfg(not(feature = "use_f64"))]
+        let span = ((step as u64 * frames as u64) >> M) as usize + 2;
+
+        let len = self.source.len();
+        let start = self.meta_seek(self.get_position() as usize).1;
+        let loop_start = self.loop_start;
+        let loop_end = loop_start + self.loop_length;
+        let ranges: [Range<usize>; 2] = match self.flags {
+            LoopType::Forward | LoopType::PingPong if start + span > loop_end => [
+                start..loop_end.max(start),
+                loop_start..(loop_start + span).min(loop_end),
+            ],
+            _ => [start..(start + span).min(len), 0..0],
+        };
+        let count = if ranges[1].is_empty() { 1 } else { 2 };
+        self.source.prefetch(&ranges[..count]);
+    }
+
+    /// Q15 frame from the decoded blocks of a block source
+    fn block_frame(&mut self, index: usize) -> (i32, i32) {
+        let block = index / self.block_len;
+        if self.blocks[0].0 != block {
+            self.blocks.swap(0, 1);
+            if self.blocks[0].0 != block {
+                self.source.decode_block(block, &mut self.blocks[0].1);
+                self.blocks[0].0 = block;
+            }
+        }
+        match self.blocks[0].1.get(index % self.block_len) {
+            Some(&(l, r)) => (l as i32, r as i32),
+            None => (0, 0),
+        }
+    }
+
+    #[inline(always)]
+    fn frame(&mut self, index: usize) -> (f32, f32) {
+        match self.block_len {
+            0 => self.source.at(index),
+            _ => {
+                let (l, r) = self.block_frame(index);
+                (l as f32 / 32768.0, r as f32 / 32768.0)
+            }
+        }
+    }
+
+    #[cfg(feature = "fixed")]
+    #[inline(always)]
+    fn frame_q15(&mut self, index: usize) -> (i32, i32) {
+        match self.block_len {
+            0 => self.source.at_q15(index),
+            _ => self.block_frame(index),
+        }
+    }
+
     fn tick(&mut self) -> (f32, f32) {
-        let useek = self.sample.meta_seek(self.get_position() as usize);
-        let u = self.sample.at(useek.1);
+        if self.is_ended() {
+            return (0.0, 0.0);
+        }
+        let useek = self.meta_seek(self.get_position() as usize);
+        let u = self.frame(useek.1);
+        if self.nearest {
+            #[cfg(not(feature = "use_f64"))]
+            {
+                self.position = ((useek.0 as FixedOrFloat) << M) | self.get_position_fraction();
+            }
+            self.increment_position();
+            return u;
+        }
         #[cfg(feature = "use_f64")]
         {
             let t = self.get_position_fraction();
-            let vseek = self.sample.meta_seek(self.get_position() as usize + 1);
-            let v = self.sample.at(vseek.1);
+            let vseek = self.meta_seek(self.get_position() as usize + 1);
+            let v = self.frame(vseek.1);
             self.increment_position();
             return (lerp(u.0, v.0, t as f32), lerp(u.1, v.1, t as f32));
         }
@@ -125,15 +302,71 @@ impl<'a> StateSample<'a> {
         {
             self.position = ((useek.0 as FixedOrFloat) << M) | self.get_position_fraction(); // update current to the smallest position
             let t = self.get_position_fraction() as f32 / (1 << M) as f32;
-            let vseek = self.sample.meta_seek(self.get_position() as usize + 1);
-            let v = self.sample.at(vseek.1);
+            let vseek = self.meta_seek(self.get_position() as usize + 1);
+            let v = self.frame(vseek.1);
             self.increment_position();
             return (lerp(u.0, v.0, t), lerp(u.1, v.1, t));
         }
     }
 
+    /// Same as `next()`, without computing the sample
+    pub fn skip(&mut self) {
+        if !self.is_enabled() {
+            return;
+        }
+        #[cfg(not(feature = "use_f64"))]
+        {
+            let useek = self.meta_seek(self.get_position() as usize);
+            self.position = ((useek.0 as FixedOrFloat) << M) | self.get_position_fraction();
+        }
+        self.increment_position();
+    }
+
+    /// Same as `next()` with integer arithmetic, Q15 values
+    #[cfg(feature = "fixed")]
+    pub fn next_q15(&mut self) -> Option<(i32, i32)> {
+        if !self.is_enabled() {
+            return None;
+        }
+        if self.is_ended() {
+            return Some((0, 0));
+        }
+        let useek = self.meta_seek(self.get_position() as usize);
+        #[cfg(not(feature = "use_f64"))]
+        {
+            self.position = ((useek.0 as FixedOrFloat) << M) | self.get_position_fraction();
+        }
+        let u = self.frame_q15(useek.1);
+        if self.nearest {
+            self.increment_position();
+            return Some(u);
+        }
+        let t = self.get_position_fraction_q15();
+        let vseek = self.meta_seek(self.get_position() as usize + 1);
+        let v = self.frame_q15(vseek.1);
+        self.increment_position();
+        Some((
+            u.0 + (((v.0 - u.0) as i64 * t) >> 15) as i32,
+            u.1 + (((v.1 - u.1) as i64 * t) >> 15) as i32,
+        ))
+    }
+
+    /// Position fraction in Q15, with `std` the position stays a `f64` for `next()`
+    #[cfg(feature = "fixed")]
+    #[inline(always)]
+    fn get_position_fraction_q15(&self) -> i64 {
+        #[cfg(feature = "use_f64")]
+        {
+            (self.get_position_fraction() * 32768.0) as i64
+        }
+        #[cfg(not(feature = "use_f64"))]
+        {
+            ((self.get_position_fraction() as i64) << 15) >> M
+        }
+    }
+
     pub fn set_position(&mut self, position: usize) {
-        if position >= self.sample.len() {
+        if position >= self.source.len() {
             self.disable();
         } else {
             #[cfg(feature = "use_f64")]
@@ -147,6 +380,80 @@ impl<'a> StateSample<'a> {
         }
     }
 
+    /// Add the next `out.len()` frames, multiplied by `volume` then `gain`, to `out`
+    ///
+    /// Same result as `next()` for each frame. Frames before the loop end are read directly from memory,
+    /// without loop resolution.
+    pub fn mix_span(&mut self, out: &mut [(f32, f32)], volume: [f32; 2], gain: [f32; 2]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let source = self.source.clone();
+        let data = match source.data() {
+            Some(data) if !self.nearest && self.block_len == 0 => data,
+            _ => {
+                for o in out.iter_mut() {
+                    let fval = self.tick();
+                    o.0 += fval.0 * volume[0] * gain[0];
+                    o.1 += fval.1 * volume[1] * gain[1];
+                }
+                return;
+            }
+        };
+        // Last frame read without loop resolution, excluded
+        let limit = match self.flags {
+            LoopType::No => source.len(),
+            LoopType::Forward | LoopType::PingPong => {
+                (self.loop_start + self.loop_length).min(source.len())
+            }
+        };
+        match data {
+            SampleDataType::Mono8(v) => self.span_loop(out, volume, gain, limit, |i| {
+                (v[i] as f32 / 128.0, v[i] as f32 / 128.0)
+            }),
+            SampleDataType::Mono16(v) => self.span_loop(out, volume, gain, limit, |i| {
+                (v[i] as f32 / 32768.0, v[i] as f32 / 32768.0)
+            }),
+            SampleDataType::Stereo8(v) => self.span_loop(out, volume, gain, limit, |i| {
+                (v[i * 2] as f32 / 128.0, v[i * 2 + 1] as f32 / 128.0)
+            }),
+            SampleDataType::Stereo16(v) => self.span_loop(out, volume, gain, limit, |i| {
+                (v[i * 2] as f32 / 32768.0, v[i * 2 + 1] as f32 / 32768.0)
+            }),
+        }
+    }
+
+    #[inline(always)]
+    fn span_loop<F: Fn(usize) -> (f32, f32)>(
+        &mut self,
+        out: &mut [(f32, f32)],
+        volume: [f32; 2],
+        gain: [f32; 2],
+        limit: usize,
+        read: F,
+    ) {
+        let Some(step) = self.step else {
+            return;
+        };
+        for o in out.iter_mut() {
+            let index = self.get_position() as usize;
+            let fval = if index + 1 < limit {
+                #[cfg(feature = "use_f64")]
+                let t = self.get_position_fraction() as f32;
+                #[cfg(not(feature = "use_f64"))]
+                let t = self.get_position_fraction() as f32 / (1 << M) as f32;
+                let u = read(index);
+                let v = read(index + 1);
+                self.position += step;
+                (lerp(u.0, v.0, t), lerp(u.1, v.1, t))
+            } else {
+                self.tick()
+            };
+            o.0 += fval.0 * volume[0] * gain[0];
+            o.1 += fval.1 * volume[1] * gain[1];
+        }
+    }
+
     #[inline(always)]
     fn increment_position(&mut self) -> FixedOrFloat {
         if let Some(step) = self.step {
@@ -163,7 +470,7 @@ impl<'a> StateSample<'a> {
     }
 
     #[inline(always)]
-    fn get_position(&mut self) -> FixedOrFloat {
+    fn get_position(&self) -> FixedOrFloat {
         #[cfg(feature = "use_f64")]
         {
             return self.position;
@@ -198,3 +505,177 @@ impl<'a> Iterator for StateSample<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::wave;
+    use alloc::vec;
+
+    /// `wave()` frames in each data format and loop type, loop in the middle
+    fn samples() -> Vec<Sample> {
+        let mono: Vec<i16> = match wave(997).data {
+            SampleDataType::Mono16(v) => v,
+            _ => unreachable!(),
+        };
+        let stereo: Vec<i16> = mono.iter().flat_map(|&s| [s, s / 3]).collect();
+        let data = [
+            SampleDataType::Mono8(mono.iter().map(|&s| (s >> 8) as i8).collect()),
+            SampleDataType::Mono16(mono.clone()),
+            SampleDataType::Stereo8(stereo.iter().map(|&s| (s >> 8) as i8).collect()),
+            SampleDataType::Stereo16(stereo),
+        ];
+        let mut samples = vec![];
+        for d in data {
+            for flags in [LoopType::No, LoopType::Forward, LoopType::PingPong] {
+                let mut sample = wave(1);
+                sample.data = d.clone();
+                sample.flags = flags;
+                sample.loop_start = 301;
+                sample.loop_length = 402;
+                samples.push(sample);
+            }
+        }
+        samples
+    }
+
+    /// Frames read directly from the module sample, as before sample sources, silence after the end
+    fn reference(sample: &Sample, position: FixedOrFloat) -> (f32, f32) {
+        #[cfg(feature = "use_f64")]
+        let index = position as usize;
+        #[cfg(not(feature = "use_f64"))]
+        let index = (position >> M) as usize;
+        if matches!(sample.flags, LoopType::No) && index >= sample.len() {
+            return (0.0, 0.0);
+        }
+        #[cfg(feature = "use_f64")]
+        {
+            let u = sample.at(sample.meta_seek(position as usize).1);
+            let v = sample.at(sample.meta_seek(position as usize + 1).1);
+            let t = position.fract() as f32;
+            (lerp(u.0, v.0, t), lerp(u.1, v.1, t))
+        }
+        #[cfg(not(feature = "use_f64"))]
+        {
+            let useek = sample.meta_seek((position >> M) as usize);
+            let u = sample.at(useek.1);
+            let position = ((useek.0 as FixedOrFloat) << M) | (position & ((1 << M) - 1));
+            let t = (position & ((1 << M) - 1)) as f32 / (1 << M) as f32;
+            let v = sample.at(sample.meta_seek((position >> M) as usize + 1).1);
+            (lerp(u.0, v.0, t), lerp(u.1, v.1, t))
+        }
+    }
+
+    /// With `std`, `fixed` doesn't change the float position
+    #[cfg(feature = "use_f64")]
+    #[test]
+    fn float_position_keeps_f64_precision() {
+        let sample = wave(997);
+        let mut state = StateSample::new(&sample, SourceRef::Borrowed(&sample), 44100.0);
+        state.set_step(44100.0 * 1.0001);
+        state.next();
+        assert!((state.get_playback_position() - 1.0001).abs() < 1e-6);
+    }
+
+    #[test]
+    fn in_memory_source_reads_module_sample() {
+        for sample in samples() {
+            let mut state = StateSample::new(&sample, SourceRef::Borrowed(&sample), 44100.0);
+            state.set_step(44100.0 * 1.37);
+            for _ in 0..3000 {
+                let expected = reference(&sample, state.position);
+                assert_eq!(state.next(), Some(expected));
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_source_reads_module_sample() {
+        use crate::sample_source::{write_pcm, FileSampleSource};
+        let path = std::env::temp_dir().join(format!("xmrsplayer-{}.pcm", std::process::id()));
+        for sample in samples() {
+            let format = write_pcm(&sample, &mut std::fs::File::create(&path).unwrap()).unwrap();
+            let file = FileSampleSource::open_blocking(&path, 0, sample.len(), format, 64).unwrap();
+            let mut memory = StateSample::new(&sample, SourceRef::Borrowed(&sample), 44100.0);
+            let mut state = StateSample::new(&sample, SourceRef::Borrowed(&file), 44100.0);
+            memory.set_step(44100.0 * 1.37);
+            state.set_step(44100.0 * 1.37);
+            for _ in 0..3000 {
+                assert_eq!(state.next(), memory.next());
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_source_loads_in_background() {
+        use crate::sample_source::{write_pcm, FileSampleSource, SampleSource};
+        let path = std::env::temp_dir().join(format!("xmrsplayer-bg-{}.pcm", std::process::id()));
+        let sample = wave(1000);
+        let format = write_pcm(&sample, &mut std::fs::File::create(&path).unwrap()).unwrap();
+        let file = FileSampleSource::open(&path, 0, sample.len(), format, 256).unwrap();
+
+        // Nothing loaded yet: silence instead of waiting for the file
+        assert_eq!(file.at(500), (0.0, 0.0));
+        assert_eq!(file.misses(), 1);
+
+        file.prefetch(&[600..700, 0..10]);
+        for _ in 0..500 {
+            if file.is_loaded(699) && file.is_loaded(0) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        for i in (0..10).chain(600..700) {
+            assert_eq!(file.at(i), SampleSource::at(&sample, i));
+        }
+        assert_eq!(file.misses(), 1);
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn new_note_reuses_block_buffers() {
+        use crate::compressed_sample::{CompressedSample, Compression, BLOCK_LEN};
+        use crate::sample_source::SampleSource;
+        use crate::state_instr_default::{InstrRef, StateInstrDefault};
+        use xmrs::prelude::*;
+
+        let len = 3 * BLOCK_LEN;
+        let deltas = (0..len).map(|i| (i % 7) as u8).collect();
+        let compressed = CompressedSample::new(Compression::Delta8, deltas, len);
+        let mut sample = wave(len);
+        sample.flags = LoopType::No;
+        let InstrumentType::Default(instr) = crate::test_module::instrument(sample).instr_type
+        else {
+            unreachable!()
+        };
+        let period_helper = PeriodHelper::new(FrequencyType::LinearFrequencies, false);
+        let mut state =
+            StateInstrDefault::new(InstrRef::Borrowed(&instr), 0, period_helper, 44100.0);
+        state.sample_source = vec![(0, SourceRef::Borrowed(&compressed))];
+
+        let capacity = |state: &StateInstrDefault| -> usize {
+            let s = state.state_sample.as_ref().unwrap();
+            s.blocks.iter().map(|b| b.1.capacity()).sum()
+        };
+        assert!(state.set_note(Note::C4));
+        let s = state.state_sample.as_mut().unwrap();
+        s.set_step(44100.0 * 1.5);
+        s.set_position(BLOCK_LEN - 10);
+        for _ in 0..20 {
+            s.next();
+        }
+        let used = capacity(&state);
+        assert!(used >= 2 * BLOCK_LEN);
+
+        // A new note keeps the buffers, without the old blocks
+        assert!(state.set_note(Note::C4));
+        assert_eq!(capacity(&state), used);
+        let s = state.state_sample.as_mut().unwrap();
+        s.set_step(44100.0);
+        assert_eq!(s.next(), Some(compressed.at(0)));
+    }
+}
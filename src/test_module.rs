@@ -0,0 +1,117 @@
+/// Small modules built by code for unit tests
+use alloc::{vec, vec::Vec};
+use xmrs::prelude::*;
+
+#[cfg(feature = "micromath")]
+#[allow(unused_imports)]
+use micromath::F32Ext;
+#[cfg(feature = "libm")]
+#[allow(unused_imports)]
+use num_traits::float::Float;
+
+/// Forward looped 16 bits sample, looping on its second half
+pub(crate) fn sample(data: Vec<i16>) -> Sample {
+    let len = data.len() as u32;
+    Sample {
+        name: "".into(),
+        loop_start: len / 2,
+        loop_length: len - len / 2,
+        volume: 1.0,
+        finetune: 0.0,
+        flags: LoopType::Forward,
+        panning: 0.5,
+        relative_note: 0,
+        data: SampleDataType::Mono16(data),
+    }
+}
+
+/// Constant `value`, for output levels
+pub(crate) fn dc(value: i16) -> Sample {
+    sample(vec![value; 256])
+}
+
+/// Two sines, not periodic on the loop: every frame counts
+pub(crate) fn wave(len: usize) -> Sample {
+    sample(
+        (0..len)
+            .map(|i| {
+                let i = i as f32;
+                (((i * 0.07).sin() * 0.8 + (i * 0.013).sin() * 0.2) * 32767.0) as i16
+            })
+            .collect(),
+    )
+}
+
+pub(crate) fn instrument(sample: Sample) -> Instrument {
+    let mut instr = InstrDefault::default();
+    instr.sample.push(sample);
+    Instrument {
+        name: "".into(),
+        instr_type: InstrumentType::Default(instr),
+        muted: false,
+    }
+}
+
+/// `rows` empty rows of `channels` slots
+pub(crate) fn pattern(rows: usize, channels: usize) -> Pattern {
+    vec![vec![PatternSlot::default(); channels]; rows]
+}
+
+pub(crate) fn note(note: Note, instrument: u8) -> PatternSlot {
+    PatternSlot {
+        note,
+        instrument,
+        ..Default::default()
+    }
+}
+
+pub(crate) fn effect(effect_type: u8, effect_parameter: u8) -> PatternSlot {
+    PatternSlot {
+        effect_type,
+        effect_parameter,
+        ..Default::default()
+    }
+}
+
+/// Linear frequencies module, patterns played in `pattern_order`
+pub(crate) fn module(
+    instruments: Vec<Sample>,
+    patterns: Vec<Pattern>,
+    pattern_order: Vec<usize>,
+) -> Module {
+    Module {
+        frequency_type: FrequencyType::LinearFrequencies,
+        instrument: instruments.into_iter().map(instrument).collect(),
+        pattern: patterns,
+        pattern_order,
+        ..Default::default()
+    }
+}
+
+/// `channels` channels playing `wave()` samples with vibrato, volume slides and panning
+///
+/// Patterns have 16 rows, one for each of the `positions` positions.
+pub(crate) fn song(channels: usize, positions: usize) -> Module {
+    let notes = [Note::C4, Note::E4, Note::G4, Note::C5, Note::A3, Note::D5];
+    let patterns = (0..positions)
+        .map(|p| {
+            let mut pattern = pattern(16, channels);
+            for (r, row) in pattern.iter_mut().enumerate() {
+                for (c, slot) in row.iter_mut().enumerate() {
+                    *slot = match (r + c) % 4 {
+                        0 => note(notes[(r + c + p) % notes.len()], 1 + (c % 2) as u8),
+                        1 => effect(0x4, 0x46),                 // vibrato
+                        2 => effect(0xA, 0x02),                 // volume slide down
+                        _ => effect(0x8, (c * 40 % 256) as u8), // panning
+                    };
+                }
+            }
+            pattern
+        })
+        .collect();
+    module(
+        vec![wave(4000), wave(1500)],
+        patterns,
+        (0..positions).collect(),
+    )
+}
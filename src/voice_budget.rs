@@ -0,0 +1,216 @@
+/// CPU budget: silent and quietest voices are not mixed
+use alloc::vec::Vec;
+
+/// Voice budget settings
+///
+/// Skipped voices keep playing (sample position, envelopes, effects) without being mixed,
+/// so they come back in time when they are selected again.
+#[derive(Clone, Copy, Debug)]
+pub struct VoiceBudget {
+    /// Voices mixed at most, the quietest ones are skipped (0 for no limit)
+    pub max_voices: usize,
+    /// Voices with a lower gain are skipped (default 0.0001, -80 dB)
+    pub silence: f32,
+}
+
+impl Default for VoiceBudget {
+    fn default() -> Self {
+        Self {
+            max_voices: 0,
+            silence: 0.0001,
+        }
+    }
+}
+
+/// Voices of the last tick
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VoiceStats {
+    /// Voices playing a sample
+    pub active: usize,
+    pub mixed: usize,
+    /// Skipped because their gain is under `silence`
+    pub silent: usize,
+    /// Skipped because of `max_voices`
+    pub over_budget: usize,
+    /// Highest `mixed` since the budget was set
+    pub max_mixed: usize,
+}
+
+/// Voices selected at each tick
+#[derive(Clone, Debug)]
+pub(crate) struct VoiceSelector {
+    budget: VoiceBudget,
+    /// Mixed or not, for each song channel then sound effect voice
+    mix: Vec<bool>,
+    /// (gain, voice) of voices over `silence`
+    loud: Vec<(f32, usize)>,
+    pub stats: VoiceStats,
+}
+
+impl VoiceSelector {
+    pub fn new(budget: VoiceBudget) -> Self {
+        Self {
+            budget,
+            mix: Vec::new(),
+            loud: Vec::new(),
+            stats: VoiceStats::default(),
+        }
+    }
+
+    /// Select voices from their gain, None for voices not playing
+    pub fn update<I: Iterator<Item = Option<f32>>>(&mut self, gains: I) {
+        self.mix.clear();
+        self.loud.clear();
+        let max_mixed = self.stats.max_mixed;
+        self.stats = VoiceStats::default();
+        for (voice, gain) in gains.enumerate() {
+            match gain {
+                Some(gain) => {
+                    self.stats.active += 1;
+                    if gain > self.budget.silence {
+                        self.loud.push((gain, voice));
+                        self.mix.push(true);
+                    } else {
+                        self.stats.silent += 1;
+                        self.mix.push(false);
+                    }
+                }
+                None => self.mix.push(false),
+            }
+        }
+
+        let max_voices = self.budget.max_voices;
+        if max_voices > 0 && self.loud.len() > max_voices {
+            self.loud.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+            for &(_, voice) in &self.loud[max_voices..] {
+                self.mix[voice] = false;
+            }
+            self.stats.over_budget = self.loud.len() - max_voices;
+        }
+        self.stats.mixed = self.stats.active - self.stats.silent - self.stats.over_budget;
+        self.stats.max_mixed = max_mixed.max(self.stats.mixed);
+    }
+
+    /// True if `voice` must be mixed, voices added since the last tick are mixed
+    #[inline(always)]
+    pub fn is_mixed(&self, voice: usize) -> bool {
+        self.mix.get(voice).copied().unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::*;
+    use crate::xmrsplayer::XmrsPlayer;
+    use alloc::{vec, vec::Vec};
+    use xmrs::prelude::*;
+
+    /// Note with a volume column from 0 to 64
+    fn loud_note(volume: u8) -> PatternSlot {
+        PatternSlot {
+            volume: 0x10 + volume,
+            ..note(Note::C4, 1)
+        }
+    }
+
+    fn render(module: &Module, budget: Option<VoiceBudget>, frames: usize) -> Vec<(f32, f32)> {
+        let mut player = XmrsPlayer::new(module, 48000.0, false);
+        player.set_voice_budget(budget);
+        let mut out = vec![(0.0, 0.0); frames];
+        assert_eq!(player.render(&mut out), frames);
+        out
+    }
+
+    #[test]
+    fn quietest_voices_over_budget() {
+        let mut selector = VoiceSelector::new(VoiceBudget {
+            max_voices: 2,
+            silence: 0.001,
+        });
+        selector.update([Some(0.5), None, Some(0.1), Some(0.0), Some(0.8), Some(0.3)].into_iter());
+        let mixed: Vec<bool> = (0..6).map(|v| selector.is_mixed(v)).collect();
+        assert_eq!(mixed, [true, false, false, false, true, false]);
+        // Voices added since the last tick
+        assert!(selector.is_mixed(6));
+        let stats = selector.stats;
+        assert_eq!(
+            (stats.active, stats.mixed, stats.silent, stats.over_budget),
+            (5, 2, 1, 2)
+        );
+
+        selector.update([Some(0.5), None].into_iter());
+        assert_eq!((selector.stats.mixed, selector.stats.max_mixed), (1, 2));
+    }
+
+    #[test]
+    fn budget_skips_the_quietest_channel() {
+        let mut row = vec![loud_note(64), loud_note(16), loud_note(48)];
+        let mut patterns = vec![pattern(2, 3)];
+        patterns[0][0].clone_from(&row);
+        let budgeted = module(vec![wave(4000)], patterns.clone(), vec![0]);
+        row[1] = PatternSlot::default();
+        patterns[0][0] = row;
+        let without_quietest = module(vec![wave(4000)], patterns, vec![0]);
+
+        let budget = VoiceBudget {
+            max_voices: 2,
+            ..Default::default()
+        };
+        let frames = 2 * 6 * 960;
+        assert_eq!(
+            render(&budgeted, Some(budget), frames),
+            render(&without_quietest, None, frames)
+        );
+        assert_ne!(
+            render(&budgeted, None, frames),
+            render(&without_quietest, None, frames)
+        );
+
+        let mut player = XmrsPlayer::new(&budgeted, 48000.0, false);
+        player.set_voice_budget(Some(budget));
+        player.step();
+        let stats = player.get_voice_stats().unwrap();
+        assert_eq!((stats.active, stats.mixed, stats.over_budget), (3, 2, 1));
+    }
+
+    #[test]
+    fn silent_voices_are_skipped() {
+        // Volume 0 on row 0, then full volume: the sample went on playing
+        let mut p = pattern(2, 1);
+        p[0][0] = loud_note(0);
+        p[1][0] = effect(0xC, 0x40);
+        let quiet = module(vec![wave(4000)], vec![p.clone()], vec![0]);
+        p[0][0] = loud_note(64);
+        let loud = module(vec![wave(4000)], vec![p], vec![0]);
+
+        let row = 6 * 960;
+        let out = render(&quiet, None, 2 * row);
+        assert!(out[..row].iter().all(|&f| f == (0.0, 0.0)));
+        assert_eq!(out[row..], render(&loud, None, 2 * row)[row..]);
+
+        let mut player = XmrsPlayer::new(&quiet, 48000.0, false);
+        player.sample(true);
+        assert!(player.channel[0].is_silent());
+        for _ in 0..row {
+            player.sample(true);
+        }
+        assert!(!player.channel[0].is_silent());
+
+        // Past the end of a sample without loop
+        let mut sample = wave(100);
+        sample.flags = LoopType::No;
+        let mut p = pattern(1, 1);
+        p[0][0] = loud_note(64);
+        let short = module(vec![sample], vec![p], vec![0]);
+        let mut player = XmrsPlayer::new(&short, 48000.0, false);
+        player.sample(true);
+        assert!(!player.channel[0].is_silent());
+        for _ in 0..row / 2 {
+            player.sample(true);
+        }
+        assert!(player.channel[0].is_silent());
+        // The last frame is not repeated
+        assert_eq!(player.sample(true), Some((0.0, 0.0)));
+    }
+}
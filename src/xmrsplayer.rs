@@ -1,11 +1,31 @@
-use crate::channel::Channel;
+use crate::channel::{Channel, ChannelDefault, ChannelInfo};
+use crate::console::Console;
+use crate::edit_session::EditCommand;
 use crate::helper::*;
 use crate::historical_helper::HistoricalHelper;
+#[cfg(feature = "std")]
+use crate::loudness::{analyze, LoudnessReport};
+use crate::meter::{MeterBallistics, Metering};
+use crate::midi::MidiSink;
+use crate::pan_law::{PanLaw, VolumeCurve};
+use crate::pattern_source::{ModuleSource, PatternSource};
+use crate::per_channel::PerChannel;
+use crate::row_visits::RowVisits;
+use crate::sample_source::{SampleSource, SourceRef};
+use crate::scope::Scope;
+use crate::sfx::{SfxChannel, SfxState};
 use crate::triggerkeep::*;
-use alloc::{vec, vec::Vec};
+use crate::voice_budget::{VoiceBudget, VoiceSelector, VoiceStats};
+use alloc::{boxed::Box, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
 use xmrs::prelude::*;
 
-pub struct XmrsPlayer<'a> {
+/// Module player
+///
+/// With `CHANNELS` > 0, channel states are kept in the player for `CHANNELS` channels, see `new_fixed()`.
+/// With the default 0, they are allocated by `new()` for the module channels.
+pub struct XmrsPlayer<'a, const CHANNELS: usize = 0> {
     pub module: &'a Module,
     sample_rate: f32,
 
@@ -32,9 +52,25 @@ pub struct XmrsPlayer<'a> {
     /// Extra ticks to be played before going to the next row - Used for EEy effect
     extra_ticks: u16,
 
-    pub channel: Vec<Channel<'a>>,
+    pub channel: PerChannel<Channel<'a>, CHANNELS>,
+    /// Gain, pan, mute and solo of song channels
+    pub console: Console,
+    /// Initial channel settings
+    channel_default: PerChannel<ChannelDefault, CHANNELS>,
+    /// Global volume set by `new()` and `goto()`
+    initial_global_volume: f32,
+    /// Amiga hard pan: 0.0 (mono) to 1.0 (full LRRL)
+    stereo_separation: f32,
+    pan_law: PanLaw,
+    volume_curve: VolumeCurve,
+    nearest: bool,
+    /// (instrument, sample, source) given to `set_sample_source()`
+    sample_source: Vec<(usize, usize, SourceRef<'a>)>,
 
-    pub row_loop_count: Vec<Vec<usize>>,
+    /// Rows already played, `loop_count` is the number of previous visits of the current row
+    ///
+    /// Replaces the `row_loop_count` table, read with `row_loop_count()`.
+    pub row_visits: RowVisits,
     pub loop_count: usize,
     pub max_loop_count: usize,
 
@@ -45,11 +81,57 @@ pub struct XmrsPlayer<'a> {
     hhelper: Option<HistoricalHelper>,
 
     pub pause: bool,
+
+    midi_sink: Option<Box<dyn MidiSink + Send + 'a>>,
+
+    /// Extra voices reserved for sound effects
+    sfx_channel: Vec<Channel<'a>>,
+    sfx_state: Vec<Option<SfxState>>,
+    /// Song channels stolen by a sound effect
+    song_sfx_state: PerChannel<Option<SfxState>, CHANNELS>,
+    sfx_count: u64,
+
+    /// Rows played by the sequencer
+    source: Box<dyn PatternSource + Send + 'a>,
+    /// `source` reads module patterns
+    module_source: bool,
+    /// Current row slots, one for each channel
+    row: PerChannel<PatternSlot, CHANNELS>,
+    /// Edit commands waiting for the next tick
+    edits: Vec<EditCommand>,
+    /// Last (position, row) played, and rows played since the beginning
+    pub(crate) played_row: (usize, usize),
+    pub(crate) played_rows: u64,
+
+    scope: Option<Scope>,
+    metering: Option<Metering>,
+    /// Frame of each channel for scope and metering, allocated when they are enabled
+    frames: Vec<(f32, f32)>,
+    voice_budget: Option<VoiceSelector>,
+    /// Global volume and amplification in Q15, updated at each tick
+    #[cfg(feature = "fixed")]
+    gain_q15: i32,
+    /// Copy of `scope` updated at each tick, for another thread
+    #[cfg(feature = "std")]
+    shared_scope: Option<Arc<Mutex<Scope>>>,
 }
 
 impl<'a> XmrsPlayer<'a> {
     pub fn new(module: &'a Module, sample_rate: f32, historical: bool) -> Self {
-        let num_channels = module.get_num_channels();
+        Self::new_fixed(module, sample_rate, historical)
+    }
+}
+
+impl<'a, const CHANNELS: usize> XmrsPlayer<'a, CHANNELS> {
+    /// Same as `new()`, with `CHANNELS` channels kept in the player
+    ///
+    /// Module channels after `CHANNELS` are not played, extra channels stay silent.
+    /// Only tables sized by the song (row visits, console) are allocated.
+    pub fn new_fixed(module: &'a Module, sample_rate: f32, historical: bool) -> Self {
+        let num_channels = match CHANNELS {
+            0 => module.get_num_channels(),
+            n => n,
+        };
         let hhelper = if historical {
             Some(HistoricalHelper::new(module.default_tempo))
         } else {
@@ -62,7 +144,7 @@ impl<'a> XmrsPlayer<'a> {
             bpm: module.default_bpm,
             global_volume: 1.0,
             amplification: 1.0,
-            row_loop_count: vec![vec![0; MAX_NUM_ROWS]; module.get_song_length()],
+            row_visits: RowVisits::new(module.get_song_length()),
             hhelper: hhelper.clone(),
             global_volume_slide_param: 0,
             current_table_index: 0,
@@ -75,20 +157,244 @@ impl<'a> XmrsPlayer<'a> {
             jump_dest: 0,
             jump_row: 0,
             extra_ticks: 0,
-            channel: vec![],
+            channel: PerChannel::new(num_channels, || {
+                Channel::new(module, sample_rate, hhelper.clone())
+            }),
+            console: Console::new(num_channels),
+            channel_default: PerChannel::new(num_channels, ChannelDefault::default),
+            initial_global_volume: 1.0,
+            stereo_separation: 1.0,
+            pan_law: PanLaw::default(),
+            volume_curve: if historical {
+                VolumeCurve::Multiplicative
+            } else {
+                VolumeCurve::default()
+            },
+            nearest: false,
+            sample_source: vec![],
             loop_count: 0,
             max_loop_count: 0,
             right_sample: None,
             #[cfg(feature = "std")]
             debug: false,
             pause: false,
+            midi_sink: None,
+            sfx_channel: vec![],
+            sfx_state: vec![],
+            song_sfx_state: PerChannel::new(num_channels, || None),
+            sfx_count: 0,
+            source: Box::new(ModuleSource::new(module)),
+            module_source: true,
+            row: PerChannel::new(num_channels, PatternSlot::default),
+            edits: vec![],
+            played_row: (0, 0),
+            played_rows: 0,
+            scope: None,
+            metering: None,
+            frames: vec![],
+            voice_budget: None,
+            #[cfg(feature = "fixed")]
+            gain_q15: 0,
+            #[cfg(feature = "std")]
+            shared_scope: None,
         };
 
-        player.channel = vec![Channel::new(module, sample_rate, hhelper.clone()); num_channels];
+        player.reserve_row_visits();
+        player.set_mix_laws(player.pan_law, player.volume_curve);
+        player.set_stereo_separation(1.0);
 
         player
     }
 
+    /// Copy of the playing state, None if rows come from another source than module patterns
+    ///
+    /// MIDI sink, scope and metering are not copied.
+    #[cfg(feature = "std")]
+    pub(crate) fn clone_state(&self) -> Option<Self> {
+        if !self.module_source {
+            return None;
+        }
+        Some(Self {
+            module: self.module,
+            sample_rate: self.sample_rate,
+            tempo: self.tempo,
+            bpm: self.bpm,
+            global_volume: self.global_volume,
+            global_volume_slide_param: self.global_volume_slide_param,
+            amplification: self.amplification,
+            current_table_index: self.current_table_index,
+            current_row: self.current_row,
+            current_tick: self.current_tick,
+            remaining_samples_in_tick: self.remaining_samples_in_tick,
+            generated_samples: self.generated_samples,
+            position_jump: self.position_jump,
+            pattern_break: self.pattern_break,
+            jump_dest: self.jump_dest,
+            jump_row: self.jump_row,
+            extra_ticks: self.extra_ticks,
+            channel: self.channel.clone(),
+            console: self.console.clone(),
+            channel_default: self.channel_default.clone(),
+            initial_global_volume: self.initial_global_volume,
+            stereo_separation: self.stereo_separation,
+            pan_law: self.pan_law,
+            volume_curve: self.volume_curve,
+            nearest: self.nearest,
+            sample_source: self.sample_source.clone(),
+            row_visits: self.row_visits.clone(),
+            loop_count: self.loop_count,
+            max_loop_count: self.max_loop_count,
+            right_sample: self.right_sample,
+            #[cfg(feature = "std")]
+            debug: false,
+            hhelper: self.hhelper,
+            pause: self.pause,
+            midi_sink: None,
+            sfx_channel: self.sfx_channel.clone(),
+            sfx_state: self.sfx_state.clone(),
+            song_sfx_state: self.song_sfx_state.clone(),
+            sfx_count: self.sfx_count,
+            source: Box::new(ModuleSource::new(self.module)),
+            module_source: true,
+            row: self.row.clone(),
+            edits: self.edits.clone(),
+            played_row: self.played_row,
+            played_rows: self.played_rows,
+            scope: None,
+            metering: None,
+            frames: vec![],
+            voice_budget: self.voice_budget.clone(),
+            #[cfg(feature = "fixed")]
+            gain_q15: self.gain_q15,
+            #[cfg(feature = "std")]
+            shared_scope: None,
+        })
+    }
+
+    /// Module loaded from a MOD file by xmrs
+    ///
+    /// `Module` doesn't keep the source format: xmrs 0.8 sets the comment to "XmRs reader" for MOD and S3M
+    /// files (only MOD uses Amiga frequencies), while XM files keep their tracker name.
+    /// A module built by code or by another loader is never detected.
+    fn is_amiga_module(&self) -> bool {
+        matches!(self.module.frequency_type, FrequencyType::AmigaFrequencies)
+            && self.module.comment == "XmRs reader"
+    }
+
+    /// Set Amiga LRRL hard pan separation, from 0.0 (mono) to 1.0 (full)
+    ///
+    /// Only MOD files loaded by xmrs use it, it replaces their channel default panning.
+    pub fn set_stereo_separation(&mut self, separation: f32) {
+        self.stereo_separation = separation.clamp(0.0, 1.0);
+        if self.is_amiga_module() {
+            let separation = self.stereo_separation;
+            for (i, default) in self.channel_default.iter_mut().enumerate() {
+                let side = if i % 4 == 0 || i % 4 == 3 { -0.5 } else { 0.5 };
+                default.panning = Some(0.5 + side * separation);
+            }
+            self.apply_channel_defaults();
+        }
+    }
+
+    /// Change how panning and volumes are computed by all channels
+    pub fn set_mix_laws(&mut self, pan_law: PanLaw, volume_curve: VolumeCurve) {
+        self.pan_law = pan_law;
+        self.volume_curve = volume_curve;
+        for ch in self.channel.iter_mut().chain(self.sfx_channel.iter_mut()) {
+            ch.set_mix_laws(pan_law, volume_curve);
+        }
+    }
+
+    /// Measure the song loudness from the beginning and set `amplification` to reach `target_lufs`
+    ///
+    /// The song is rendered once from module patterns, with current settings.
+    /// Gain is computed with initial global volume: the target can be missed if the song changes it.
+    /// Returns loudness measured before the change.
+    #[cfg(feature = "std")]
+    pub fn auto_gain(&mut self, target_lufs: f64) -> LoudnessReport {
+        let mut analysis = XmrsPlayer::new(self.module, self.sample_rate, self.hhelper.is_some());
+        analysis.amplification = self.amplification;
+        analysis.set_mix_laws(self.pan_law, self.volume_curve);
+        analysis.set_initial_global_volume(self.initial_global_volume);
+        for (default, own) in analysis
+            .channel_default
+            .iter_mut()
+            .zip(self.channel_default.iter())
+        {
+            *default = *own;
+        }
+        analysis.apply_channel_defaults();
+        let report = analyze(&mut analysis);
+
+        let g = self.initial_global_volume;
+        if report.integrated.is_finite() && g > 0.0 {
+            let k = 10f64.powf((target_lufs - report.integrated) / 20.0) as f32;
+            let a = self.amplification;
+            // Same formula as samples_apply_volume(): gain = g*a / (g+a)
+            let gain = (g * a) / (g + a) * k;
+            self.amplification = if gain < g * 0.999 {
+                gain * g / (g - gain)
+            } else {
+                1000.0 * g
+            };
+        }
+        report
+    }
+
+    /// Read frames of `sample` in `instrument` from `source` instead of module memory, from the next note
+    ///
+    /// Loop points, volume and panning still come from the module sample. With a streamed source,
+    /// module sample data can be left empty.
+    pub fn set_sample_source(
+        &mut self,
+        instrument: usize,
+        sample: usize,
+        source: &'a dyn SampleSource,
+    ) {
+        self.replace_sample_source(instrument, sample, SourceRef::Borrowed(source));
+    }
+
+    fn replace_sample_source(&mut self, instrument: usize, sample: usize, source: SourceRef<'a>) {
+        for ch in self.channel.iter_mut().chain(self.sfx_channel.iter_mut()) {
+            ch.set_sample_source(instrument, sample, source.clone());
+        }
+        self.sample_source
+            .retain(|s| s.0 != instrument || s.1 != sample);
+        self.sample_source.push((instrument, sample, source));
+    }
+
+    /// Linear interpolation of samples (default), or nearest neighbour: faster but less accurate
+    pub fn set_interpolation(&mut self, interpolate: bool) {
+        self.nearest = !interpolate;
+        for ch in self.channel.iter_mut().chain(self.sfx_channel.iter_mut()) {
+            ch.set_nearest(self.nearest);
+        }
+    }
+
+    /// Change initial settings of a channel, applied now and by `goto()`
+    pub fn set_channel_default(&mut self, channel: usize, default: ChannelDefault) {
+        if let Some(d) = self.channel_default.get_mut(channel) {
+            *d = default;
+            self.channel[channel].set_default(&default);
+        }
+    }
+
+    pub fn get_channel_default(&self, channel: usize) -> Option<ChannelDefault> {
+        self.channel_default.get(channel).copied()
+    }
+
+    /// Change global volume set by `goto()`, and current global volume
+    pub fn set_initial_global_volume(&mut self, volume: f32) {
+        self.initial_global_volume = volume.clamp(0.0, 1.0);
+        self.global_volume = self.initial_global_volume;
+    }
+
+    fn apply_channel_defaults(&mut self) {
+        for (ch, default) in self.channel.iter_mut().zip(&self.channel_default) {
+            ch.set_default(default);
+        }
+    }
+
     #[cfg(feature = "std")]
     pub fn debug(&mut self, debug: bool) {
         self.debug = debug;
@@ -106,8 +412,334 @@ impl<'a> XmrsPlayer<'a> {
         }
     }
 
+    /// Send MIDI events for instruments with MIDI output enabled
+    pub fn set_midi_sink(&mut self, sink: Option<Box<dyn MidiSink + Send + 'a>>) {
+        self.midi_sink = sink;
+    }
+
+    /// Returns the MIDI sink, for example to write a SMF file at the end
+    pub fn take_midi_sink(&mut self) -> Option<Box<dyn MidiSink + Send + 'a>> {
+        self.midi_sink.take()
+    }
+
+    /// Play rows from another source than module patterns, for example generated by code
+    ///
+    /// Module instruments are still used. Song position is reset to the beginning.
+    pub fn set_pattern_source(&mut self, source: Box<dyn PatternSource + Send + 'a>) {
+        self.row_visits = RowVisits::new(source.song_length());
+        self.source = source;
+        self.module_source = false;
+        self.reserve_row_visits();
+        self.loop_count = 0;
+        self.current_table_index = 0;
+        self.current_row = 0;
+        self.current_tick = 0;
+        self.remaining_samples_in_tick = 0.0;
+        self.position_jump = false;
+        self.pattern_break = false;
+        self.jump_row = 0;
+    }
+
+    /// Room for all rows in `row_visits`, so that the sequencer doesn't allocate
+    fn reserve_row_visits(&mut self) {
+        for position in 0..self.source.song_length() {
+            let rows = self.source.num_rows(position);
+            self.row_visits.reserve(position, rows);
+        }
+    }
+
+    /// Queue an edit command, applied before the next tick
+    ///
+    /// Pattern and order list commands need an editable source, like `EditSession`.
+    /// If the current pattern shrinks under the next row, playback goes on with the next position.
+    /// Inserting or deleting rows or positions restarts `row_visits`, so `loop_count` counts from there.
+    pub fn edit(&mut self, command: EditCommand) {
+        self.edits.push(command);
+    }
+
+    fn apply_edits(&mut self) {
+        if self.edits.is_empty() {
+            return;
+        }
+        for command in core::mem::take(&mut self.edits) {
+            match command {
+                EditCommand::ReplaceInstrument { instrument, data } => {
+                    for ch in self.channel.iter_mut().chain(self.sfx_channel.iter_mut()) {
+                        ch.replace_instrument(instrument, data.clone());
+                    }
+                }
+                EditCommand::ReplaceSample {
+                    instrument,
+                    sample,
+                    data,
+                } => {
+                    self.replace_sample_source(instrument, sample, SourceRef::Shared(data));
+                }
+                EditCommand::InsertRow { .. }
+                | EditCommand::DeleteRow { .. }
+                | EditCommand::InsertOrder { .. }
+                | EditCommand::DeleteOrder { .. } => {
+                    // Rows or positions moved: visits are counted again from here
+                    if self.source.edit(&command) {
+                        self.row_visits.clear();
+                    }
+                }
+                _ => {
+                    self.source.edit(&command);
+                }
+            }
+        }
+
+        // Keep song position consistent with the new order list and patterns
+        let song_length = self.source.song_length();
+        self.row_visits.resize(song_length);
+        self.reserve_row_visits();
+        if self.jump_dest >= song_length {
+            self.jump_dest = self.source.restart_position();
+        }
+        if self.current_table_index >= song_length {
+            self.current_table_index = self.source.restart_position();
+            self.current_row = 0;
+        }
+        let num_rows = self.source.num_rows(self.current_table_index);
+        for ch in &mut self.channel {
+            if ch.pattern_loop_origin >= num_rows {
+                ch.pattern_loop_origin = 0;
+                ch.pattern_loop_count = 0;
+            }
+        }
+        if self.current_row >= num_rows && !self.position_jump && !self.pattern_break {
+            self.current_table_index += 1;
+            self.current_row = 0;
+            self.post_pattern_change();
+        }
+    }
+
+    /// Keep the last `length` samples of each channel and of the output, 0 to disable
+    pub fn set_scope(&mut self, length: usize) {
+        self.scope = if length == 0 {
+            None
+        } else {
+            let num_channels = self.channel.len() + self.sfx_channel.len();
+            Some(Scope::new(num_channels, length, self.sample_rate))
+        };
+        self.reserve_frames();
+        #[cfg(feature = "std")]
+        if let (Some(scope), Some(shared)) = (&self.scope, &self.shared_scope) {
+            *shared.lock().unwrap() = scope.clone();
+        }
+    }
+
+    /// Scope buffers, if enabled with `set_scope()`
+    pub fn get_scope(&self) -> Option<&Scope> {
+        self.scope.as_ref()
+    }
+
+    /// Scope buffers readable from another thread, updated at each tick
+    ///
+    /// The audio thread never waits: if the scope is locked, it is updated at next tick.
+    #[cfg(feature = "std")]
+    pub fn share_scope(&mut self) -> Option<Arc<Mutex<Scope>>> {
+        let scope = self.scope.as_ref()?;
+        let shared = self
+            .shared_scope
+            .get_or_insert_with(|| Arc::new(Mutex::new(scope.clone())));
+        Some(Arc::clone(shared))
+    }
+
+    fn update_scope(&mut self) {
+        let Some(scope) = &mut self.scope else {
+            return;
+        };
+        for (buffer, ch) in scope
+            .channel
+            .iter_mut()
+            .zip(self.channel.iter().chain(self.sfx_channel.iter()))
+        {
+            // A sample played at 8363 Hz is a C-4
+            buffer.frequency = ch.get_frequency() * (261.6256 / 8363.0);
+        }
+        #[cfg(feature = "std")]
+        if let Some(shared) = &self.shared_scope {
+            if let Ok(mut s) = shared.try_lock() {
+                s.copy_from(scope);
+            }
+        }
+    }
+
+    /// Compute level meters while mixing, None to disable
+    pub fn set_metering(&mut self, ballistics: Option<MeterBallistics>) {
+        self.metering = ballistics.map(|b| {
+            let num_channels = self.channel.len() + self.sfx_channel.len();
+            Metering::new(b, num_channels, self.sample_rate)
+        });
+        self.reserve_frames();
+    }
+
+    /// Room for a frame of each channel if scope or metering needs them
+    fn reserve_frames(&mut self) {
+        let len = match self.scope.is_some() || self.metering.is_some() {
+            true => self.channel.len() + self.sfx_channel.len(),
+            false => 0,
+        };
+        self.frames.resize(len, (0.0, 0.0));
+    }
+
+    /// Level meters, if enabled with `set_metering()`
+    ///
+    /// Master meters are updated by `samples_apply_volume()`.
+    pub fn get_metering(&self) -> Option<&Metering> {
+        self.metering.as_ref()
+    }
+
+    /// Reset true peaks and clipped samples count
+    pub fn reset_metering(&mut self) {
+        if let Some(m) = &mut self.metering {
+            m.reset();
+        }
+    }
+
+    /// Limit mixed voices to save CPU time, None to mix all voices
+    ///
+    /// Voices with no sample or a zero volume are never mixed. With a budget, voices under `silence`
+    /// are not mixed either, then the quietest ones over `max_voices`. Voices are selected at each tick.
+    pub fn set_voice_budget(&mut self, budget: Option<VoiceBudget>) {
+        self.voice_budget = budget.map(VoiceSelector::new);
+    }
+
+    /// Voices of the last tick, if a voice budget is set
+    pub fn get_voice_stats(&self) -> Option<VoiceStats> {
+        self.voice_budget.as_ref().map(|b| b.stats)
+    }
+
+    fn update_voice_budget(&mut self) {
+        if let Some(budget) = &mut self.voice_budget {
+            budget.update(
+                self.channel
+                    .iter()
+                    .chain(self.sfx_channel.iter())
+                    .map(|ch| ch.loudness()),
+            );
+        }
+    }
+
+    /// Reserve extra voices for sound effects, mixed after song channels
+    pub fn set_sfx_voices(&mut self, voices: usize) {
+        let mut ch = Channel::new(self.module, self.sample_rate, self.hhelper);
+        ch.set_mix_laws(self.pan_law, self.volume_curve);
+        ch.set_nearest(self.nearest);
+        for (instrument, sample, source) in &self.sample_source {
+            ch.set_sample_source(*instrument, *sample, source.clone());
+        }
+        self.sfx_channel = vec![ch; voices];
+        self.sfx_state = vec![None; voices];
+        self.reserve_frames();
+    }
+
+    /// Play a pattern slot (note, instrument, volume and effect) over the song
+    ///
+    /// If `steal` is a song channel, the sound effect replaces it until the song plays a new note on it,
+    /// else a reserved voice is used. A playing sound effect can only be replaced by a sound effect with the same or a higher priority.
+    /// Returns None if no channel is available.
+    pub fn play_sfx(
+        &mut self,
+        slot: &PatternSlot,
+        priority: u8,
+        steal: Option<usize>,
+    ) -> Option<SfxChannel> {
+        let state = SfxState::new(slot, priority, self.sfx_count);
+        let sfx = match steal {
+            Some(ch_index) => {
+                if ch_index >= self.channel.len() {
+                    return None;
+                }
+                if let Some(current) = &self.song_sfx_state[ch_index] {
+                    if current.priority > priority && self.channel[ch_index].is_playing() {
+                        return None;
+                    }
+                }
+                self.song_sfx_state[ch_index] = Some(state);
+                self.channel[ch_index].tick0(slot);
+                SfxChannel::Song(ch_index)
+            }
+            None => {
+                let index = self.sfx_allocate(priority)?;
+                self.sfx_state[index] = Some(state);
+                self.sfx_channel[index].tick0(slot);
+                SfxChannel::Reserved(index)
+            }
+        };
+        self.sfx_count += 1;
+        Some(sfx)
+    }
+
+    /// Change the effect of a playing sound effect
+    pub fn set_sfx_effect(&mut self, sfx: SfxChannel, effect_type: u8, effect_parameter: u8) {
+        let Some((ch, state)) = self.sfx_get(sfx) else {
+            return;
+        };
+        if let Some(state) = state {
+            state.effect_type = effect_type;
+            state.effect_parameter = effect_parameter;
+            ch.tick0(&state.row_slot());
+        }
+    }
+
+    /// Key off a sound effect, a stolen song channel is given back to the song
+    pub fn stop_sfx(&mut self, sfx: SfxChannel) {
+        let key_off = PatternSlot {
+            note: Note::KeyOff,
+            ..Default::default()
+        };
+        let Some((ch, state)) = self.sfx_get(sfx) else {
+            return;
+        };
+        if state.take().is_some() {
+            ch.tick0(&key_off);
+        }
+    }
+
+    fn sfx_get(&mut self, sfx: SfxChannel) -> Option<(&mut Channel<'a>, &mut Option<SfxState>)> {
+        match sfx {
+            SfxChannel::Reserved(i) if i < self.sfx_channel.len() => {
+                Some((&mut self.sfx_channel[i], &mut self.sfx_state[i]))
+            }
+            SfxChannel::Song(i) if i < self.channel.len() => {
+                Some((&mut self.channel[i], &mut self.song_sfx_state[i]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Free voice first, then the oldest voice with a lower or same priority
+    fn sfx_allocate(&mut self, priority: u8) -> Option<usize> {
+        for (state, ch) in self.sfx_state.iter_mut().zip(&self.sfx_channel) {
+            if !ch.is_playing() {
+                *state = None;
+            }
+        }
+        if let Some(i) = self.sfx_state.iter().position(|s| s.is_none()) {
+            return Some(i);
+        }
+        self.sfx_state
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.map(|s| (i, s)))
+            .filter(|(_, s)| s.priority <= priority)
+            .min_by_key(|(_, s)| (s.priority, s.age))
+            .map(|(i, _)| i)
+    }
+
+    /// Stop after `max_loop_count` song loops, 0 to play forever
+    ///
+    /// Row visits are counted up to `RowVisits::MAX_VISITS` (255), higher values are clamped to it.
     pub fn set_max_loop_count(&mut self, max_loop_count: usize) {
-        self.max_loop_count = max_loop_count;
+        self.max_loop_count = max_loop_count.min(RowVisits::MAX_VISITS);
+    }
+
+    /// Number of times the row was played at this song position, up to `RowVisits::MAX_VISITS`
+    pub fn row_loop_count(&self, position: usize, row: usize) -> usize {
+        self.row_visits.visits(position, row)
     }
 
     pub fn get_loop_count(&self) -> usize {
@@ -129,9 +761,8 @@ impl<'a> XmrsPlayer<'a> {
     /// Jump to row at index table_position in pattern_order at speed
     /// if speed == 0, resets to default speed
     pub fn goto(&mut self, table_position: usize, row: usize, speed: u16) -> bool {
-        if table_position < self.module.get_song_length() {
-            let num_row = self.module.pattern_order[table_position];
-            if row < self.module.get_num_rows(num_row) {
+        if table_position < self.source.song_length() {
+            if row < self.source.num_rows(table_position) {
                 // Create a position jump
                 self.jump_dest = table_position;
                 self.jump_row = row;
@@ -144,13 +775,13 @@ impl<'a> XmrsPlayer<'a> {
                     speed
                 };
                 self.bpm = self.module.default_bpm;
-                self.global_volume = 1.0;
+                self.global_volume = self.initial_global_volume;
 
                 // Cleanup channels
-                let num_channels = self.module.get_num_channels();
-                for i in 0..num_channels {
-                    self.channel[i].trigger_note(TRIGGER_KEEP_PERIOD); // clean what we can
+                for ch in &mut self.channel {
+                    ch.trigger_note(TRIGGER_KEEP_PERIOD); // clean what we can
                 }
+                self.apply_channel_defaults();
 
                 // next() must call tick() then row()
                 self.remaining_samples_in_tick = 0.0;
@@ -166,8 +797,21 @@ impl<'a> XmrsPlayer<'a> {
     }
 
     /// Returns current pattern number in pattern_order
+    ///
+    /// Read from the pattern source, 0 if it doesn't give pattern numbers.
     pub fn get_current_pattern(&self) -> usize {
-        self.module.pattern_order[self.current_table_index]
+        self.source
+            .pattern_at(self.current_table_index)
+            .unwrap_or(0)
+    }
+
+    /// Returns a snapshot of each song channel, followed by sound effect voices
+    pub fn channel_info(&self) -> Vec<ChannelInfo> {
+        self.channel
+            .iter()
+            .chain(self.sfx_channel.iter())
+            .map(|ch| ch.info())
+            .collect()
     }
 
     /// Returns current index in pattern_order
@@ -187,27 +831,27 @@ impl<'a> XmrsPlayer<'a> {
 
     fn post_pattern_change(&mut self) {
         /* Loop if necessary */
-        if self.current_table_index >= self.module.pattern_order.len() {
-            self.current_table_index = self.module.restart_position;
+        if self.current_table_index >= self.source.song_length() {
+            self.current_table_index = self.source.restart_position();
         }
 
         #[cfg(feature = "std")]
         if self.debug {
             println!(
                 "pattern_order[0x{:03x}] = 0x{:03x}",
-                self.current_table_index, self.module.pattern_order[self.current_table_index]
+                self.current_table_index,
+                self.get_current_pattern()
             );
         }
     }
 
-    fn tick0_global_effects(&mut self, ch_index: usize) {
+    fn tick0_global_effects(&mut self, ch_index: usize, pattern_slot: &PatternSlot) {
         let ch = &mut self.channel[ch_index];
-        let pattern_slot = &ch.current;
 
         match pattern_slot.effect_type {
             0xB => {
                 /* Bxx: Position jump */
-                if (pattern_slot.effect_parameter as usize) < self.module.pattern_order.len() {
+                if (pattern_slot.effect_parameter as usize) < self.source.song_length() {
                     self.position_jump = true;
                     self.jump_dest = pattern_slot.effect_parameter as usize;
                     self.jump_row = 0;
@@ -297,51 +941,72 @@ impl<'a> XmrsPlayer<'a> {
             self.post_pattern_change();
         }
 
-        let pat_idx_temp = self.module.pattern_order[self.current_table_index];
-        let pat_idx = if pat_idx_temp < self.module.pattern.len() {
-            pat_idx_temp
-        } else {
+        let mut pattern_len = self.source.num_rows(self.current_table_index);
+        if pattern_len == 0 {
             // empty pattern, returning to zero
             self.current_table_index = 0;
-            self.module.pattern_order[self.current_table_index]
-        };
+            pattern_len = self.source.num_rows(self.current_table_index);
+        }
 
-        let num_channels = self.module.get_num_channels();
         let mut in_a_loop = false;
 
         let current_row = self.current_row;
+        self.played_row = (self.current_table_index, current_row);
+        self.played_rows += 1;
+        let mut row = core::mem::take(&mut self.row);
+        self.source
+            .get_row(self.current_table_index, current_row, &mut row);
         #[cfg(feature = "std")]
         if self.debug {
             print!("{:03X} ", current_row);
         }
-        for ch_index in 0..num_channels {
-            let ps = &self.module.pattern[pat_idx][current_row][ch_index];
+        for (ch_index, ps) in row.iter().enumerate() {
             #[cfg(feature = "std")]
             if self.debug {
                 print!("{:?}", ps);
             }
-            self.channel[ch_index].tick0(ps);
-            self.tick0_global_effects(ch_index);
+            match self.song_sfx_state[ch_index] {
+                Some(sfx) if !ps.note.is_valid() => {
+                    // Channel is still used by a sound effect
+                    self.channel[ch_index].tick0(&sfx.row_slot());
+                }
+                _ => {
+                    self.song_sfx_state[ch_index] = None;
+                    self.channel[ch_index].tick0(ps);
+                }
+            }
+            self.tick0_global_effects(ch_index, ps);
             if !in_a_loop && self.channel[ch_index].pattern_loop_count > 0 {
                 in_a_loop = true;
             }
         }
+        self.row = row;
         #[cfg(feature = "std")]
         if self.debug {
             println!();
         }
 
+        for (ch, state) in self.sfx_channel.iter_mut().zip(&self.sfx_state) {
+            match state {
+                Some(sfx) => ch.tick0(&sfx.row_slot()),
+                None => ch.tick0(&PatternSlot::default()),
+            }
+        }
+
         if !in_a_loop {
             /* No E6y loop is in effect (or we are in the first pass) */
-            self.loop_count = self.row_loop_count[self.current_table_index][self.current_row];
-            self.row_loop_count[self.current_table_index][self.current_row] += 1;
+            if let Some(count) = self
+                .row_visits
+                .visit(self.current_table_index, self.current_row)
+            {
+                self.loop_count = count;
+            }
         }
 
         self.current_row = self.current_row.wrapping_add(1); /* Maybe this can be an u8 on old computers, this line can
                                                               * increment from 255 to 0, in which case it
                                                               * is still necessary to go the next
                                                               * pattern. */
-        let pattern_len = self.module.pattern[pat_idx].len();
 
         if !self.position_jump
             && !self.pattern_break
@@ -376,63 +1041,218 @@ impl<'a> XmrsPlayer<'a> {
 
             clamp(&mut self.global_volume);
         }
+
+        for ch in &mut self.sfx_channel {
+            ch.tick(self.current_tick);
+        }
     }
 
-    pub fn step(&mut self) {
-        if self.remaining_samples_in_tick <= 0.0 {
-            if self.current_tick == 0 {
-                self.tick0();
-            } else {
-                self.tick();
-            }
+    /// Play one tick of the sequencer without generating samples
+    pub(crate) fn step_tick(&mut self) {
+        self.sequence_tick();
+        self.end_tick();
+    }
 
-            self.current_tick += 1;
-            if self.current_tick >= self.tempo + self.extra_ticks {
-                self.current_tick = 0;
-                self.extra_ticks = 0;
-            }
+    /// Notes triggered by the last tick are only reported to MIDI until the next one, sink or not
+    pub(crate) fn end_tick(&mut self) {
+        for ch in &mut self.channel {
+            ch.triggered = false;
+        }
+    }
 
-            if let Some(hhelper) = &mut self.hhelper {
-                hhelper.set_tempo(self.tempo);
+    /// `step_tick()` without `end_tick()`, to read the tick triggers
+    pub(crate) fn sequence_tick(&mut self) {
+        self.apply_edits();
+
+        if self.current_tick == 0 {
+            self.tick0();
+        } else {
+            self.tick();
+        }
+
+        self.current_tick += 1;
+        if self.current_tick >= self.tempo + self.extra_ticks {
+            self.current_tick = 0;
+            self.extra_ticks = 0;
+        }
+
+        if let Some(sink) = &mut self.midi_sink {
+            for ch in &mut self.channel {
+                ch.midi_tick(self.generated_samples, sink.as_mut(), None);
             }
+        }
+
+        if let Some(hhelper) = &mut self.hhelper {
+            hhelper.set_tempo(self.tempo);
+        }
+
+        self.update_console();
+        self.update_voice_budget();
+        self.update_scope();
+        #[cfg(feature = "fixed")]
+        {
+            let fgvol = (self.global_volume * self.amplification)
+                / (self.global_volume + self.amplification);
+            self.gain_q15 = (fgvol * 32768.0) as i32;
+        }
+    }
+
+    fn update_console(&mut self) {
+        let ramp = 1.0 / (self.console.ramp_time * self.sample_rate).max(1.0);
+        for (i, ch) in self.channel.iter_mut().enumerate() {
+            let gains = self.console.gains(i, ch.instrument_num());
+            ch.set_mix_gain(gains, ramp);
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.remaining_samples_in_tick <= 0.0 {
+            self.step_tick();
             /* FT2 manual says number of ticks / second = BPM * 0.4 */
             self.remaining_samples_in_tick += self.sample_rate / (self.bpm as f32 * 0.4);
+            if !self.sample_source.is_empty() {
+                let frames = self.remaining_samples_in_tick.max(0.0) as usize + 1;
+                for ch in self.channel.iter().chain(self.sfx_channel.iter()) {
+                    ch.prefetch(frames);
+                }
+            }
         }
         self.remaining_samples_in_tick -= 1.0;
     }
 
+    /// true if `max_loop_count` is reached
+    pub fn is_finished(&self) -> bool {
+        self.max_loop_count > 0 && self.loop_count >= self.max_loop_count.min(RowVisits::MAX_VISITS)
+    }
+
+    /// Same as `samples_from_channels()`, moving sample positions without computing samples
+    ///
+    /// Returns false if no more samples are available, or if paused: nothing moves.
+    #[cfg(feature = "std")]
+    pub(crate) fn skip_sample(&mut self) -> bool {
+        if self.pause {
+            return false;
+        }
+        self.step();
+        if self.is_finished() {
+            return false;
+        }
+        for ch in self.channel.iter_mut().chain(self.sfx_channel.iter_mut()) {
+            ch.skip();
+        }
+        self.generated_samples += 1;
+        true
+    }
+
+    /// Returns next (left,right) sample, mixed with integer arithmetic
+    ///
+    /// Only the mixer is integer: samples are interpolated, scaled and mixed without floats. Sequencer,
+    /// effects, envelopes and periods use floats once per tick (software floats on targets without FPU),
+    /// then volumes and gains are converted to integers. There are no integer period tables nor integer
+    /// envelopes. Global volume and amplification changes are applied at next tick.
+    /// Scope and metering are not updated.
+    #[cfg(feature = "fixed")]
+    pub fn sample_i16(&mut self) -> Option<(i16, i16)> {
+        if self.pause {
+            return Some((0, 0));
+        }
+        self.step();
+        if self.is_finished() {
+            return None;
+        }
+        let mut sum = (0i32, 0i32);
+        for (i, ch) in self
+            .channel
+            .iter_mut()
+            .chain(self.sfx_channel.iter_mut())
+            .enumerate()
+        {
+            if ch.is_silent() || self.voice_budget.as_ref().is_some_and(|b| !b.is_mixed(i)) {
+                ch.skip();
+                continue;
+            }
+            if let Some(fval) = ch.next_q15() {
+                if !ch.is_muted() {
+                    sum.0 += fval.0;
+                    sum.1 += fval.1;
+                }
+            }
+        }
+        self.generated_samples += 1;
+        let left = ((sum.0 as i64 * self.gain_q15 as i64) >> 15).clamp(-32768, 32767);
+        let right = ((sum.1 as i64 * self.gain_q15 as i64) >> 15).clamp(-32768, 32767);
+        Some((left as i16, right as i16))
+    }
+
     /// Returns samples from each channel before applying global volume and amplification.
     /// If the function returns None, no more samples are available.
     ///
+    /// Sound effect voices reserved with `set_sfx_voices()` follow song channels.
+    ///
     /// In conjunction with the samples_apply_volume() function, this function can be used to replace the iterator or the sample() function if you want to control each channel in fine detail, for example, to create beautiful graphic effects.
+    ///
+    /// The returned `Vec` is allocated at each call, `samples_from_channels_into()` reuses a buffer.
     pub fn samples_from_channels(&mut self) -> Option<Vec<(f32, f32)>> {
+        let mut samples = vec![(0.0, 0.0); self.channel.len() + self.sfx_channel.len()];
+        if self.samples_from_channels_into(&mut samples) {
+            Some(samples)
+        } else {
+            None
+        }
+    }
+
+    /// Same as `samples_from_channels()`, without allocating: `samples` gets the value of each channel
+    /// (song channels, then sound effect voices). Returns false if no more samples are available.
+    pub fn samples_from_channels_into(&mut self, samples: &mut [(f32, f32)]) -> bool {
         if self.pause {
-            return Some(vec![(0.0, 0.0); self.channel.len()]);
+            samples.fill((0.0, 0.0));
+            return true;
         }
 
         self.step();
 
-        if self.max_loop_count > 0 && self.loop_count >= self.max_loop_count {
-            return None;
+        if self.is_finished() {
+            return false;
         }
 
-        let samples: Vec<(f32, f32)> = self
+        for (i, ch) in self
             .channel
             .iter_mut()
-            .map(|ch| match ch.next() {
-                Some(fval) => {
-                    if ch.is_muted() {
-                        (0.0, 0.0)
-                    } else {
-                        fval
-                    }
-                }
-                None => (0.0, 0.0),
-            })
-            .collect();
+            .chain(self.sfx_channel.iter_mut())
+            .enumerate()
+        {
+            let mixed = self.voice_budget.as_ref().map_or(true, |b| b.is_mixed(i));
+            let value = Self::channel_frame(ch, mixed);
+            if let Some(s) = samples.get_mut(i) {
+                *s = value;
+            }
+        }
+
+        if let Some(metering) = &mut self.metering {
+            metering.update_channels(samples);
+        }
+        if self.scope.is_some() {
+            let master = self.mix(samples);
+            if let Some(scope) = &mut self.scope {
+                scope.push(samples, master);
+            }
+        }
 
         self.generated_samples += 1;
-        Some(samples)
+        true
+    }
+
+    /// Next frame of a channel, silent if not `mixed` by the voice budget or muted
+    #[inline(always)]
+    fn channel_frame(ch: &mut Channel<'a>, mixed: bool) -> (f32, f32) {
+        if ch.is_silent() || !mixed {
+            ch.skip();
+            return (0.0, 0.0);
+        }
+        match ch.next() {
+            Some(fval) if !ch.is_muted() => fval,
+            _ => (0.0, 0.0),
+        }
     }
 
     pub fn samples_to_sample(&mut self, samples: &Vec<(f32, f32)>) -> (f32, f32) {
@@ -445,24 +1265,132 @@ impl<'a> XmrsPlayer<'a> {
     }
 
     /// This function applies volume and amplification to the various channel samples. It is applied to the result of the `samples_from_channels()` function.
-    pub fn samples_apply_volume(&mut self, samples: &Vec<(f32, f32)>) -> (f32, f32) {
+    pub fn samples_apply_volume(&mut self, samples: &[(f32, f32)]) -> (f32, f32) {
+        let sample = self.mix(samples);
+        if let Some(metering) = &mut self.metering {
+            metering.update_master(sample);
+        }
+        sample
+    }
+
+    fn mix(&self, samples: &[(f32, f32)]) -> (f32, f32) {
         let fgvol =
             (self.global_volume * self.amplification) / (self.global_volume + self.amplification);
-        let sample = self.samples_to_sample(samples);
-        return (sample.0 * fgvol, sample.1 * fgvol);
+        let sample = samples
+            .iter()
+            .fold((0.0, 0.0), |(acc_left, acc_right), (left, right)| {
+                (acc_left + left, acc_right + right)
+            });
+        (sample.0 * fgvol, sample.1 * fgvol)
     }
 
     /// Returns the sum of the samples from the `samples_from_channels()` and `samples_apply_volume()` functions, separating the left channel from the right.
+    ///
+    /// Doesn't allocate: channels are summed directly, or through a buffer kept for scope and metering.
     pub fn sample(&mut self, apply_volume: bool) -> Option<(f32, f32)> {
-        if let Some(samples) = self.samples_from_channels() {
-            if apply_volume {
-                return Some(self.samples_apply_volume(&samples));
+        if self.scope.is_some() || self.metering.is_some() {
+            let mut frames = core::mem::take(&mut self.frames);
+            let sample = if self.samples_from_channels_into(&mut frames) {
+                Some(if apply_volume {
+                    self.samples_apply_volume(&frames)
+                } else {
+                    self.samples_to_sample(&frames)
+                })
             } else {
-                return Some(self.samples_to_sample(&samples));
-            }
-        } else {
+                None
+            };
+            self.frames = frames;
+            return sample;
+        }
+
+        if self.pause {
+            return Some((0.0, 0.0));
+        }
+        self.step();
+        if self.is_finished() {
             return None;
         }
+        let mut sample = (0.0, 0.0);
+        for (i, ch) in self
+            .channel
+            .iter_mut()
+            .chain(self.sfx_channel.iter_mut())
+            .enumerate()
+        {
+            let mixed = self.voice_budget.as_ref().map_or(true, |b| b.is_mixed(i));
+            let value = Self::channel_frame(ch, mixed);
+            sample.0 += value.0;
+            sample.1 += value.1;
+        }
+        self.generated_samples += 1;
+        if apply_volume {
+            sample = self.mix(&[sample]);
+        }
+        Some(sample)
+    }
+
+    /// Render up to `out.len()` frames, same as calling `sample(true)` for each frame
+    ///
+    /// Channels are mixed one after the other by spans of frames between two ticks.
+    /// Returns the number of frames written, lower than `out.len()` at the end of the song.
+    pub fn render(&mut self, out: &mut [(f32, f32)]) -> usize {
+        if self.scope.is_some() || self.metering.is_some() {
+            for (written, o) in out.iter_mut().enumerate() {
+                match self.sample(true) {
+                    Some(sample) => *o = sample,
+                    None => return written,
+                }
+            }
+            return out.len();
+        }
+
+        let mut written = 0;
+        while written < out.len() {
+            if self.pause {
+                out[written..].fill((0.0, 0.0));
+                return out.len();
+            }
+            self.step();
+            if self.is_finished() {
+                break;
+            }
+            // Frames until next tick
+            let mut len = 1;
+            while len < out.len() - written && self.remaining_samples_in_tick > 0.0 {
+                self.remaining_samples_in_tick -= 1.0;
+                len += 1;
+            }
+
+            let span = &mut out[written..written + len];
+            span.fill((0.0, 0.0));
+            for (i, ch) in self
+                .channel
+                .iter_mut()
+                .chain(self.sfx_channel.iter_mut())
+                .enumerate()
+            {
+                if ch.is_silent() || self.voice_budget.as_ref().is_some_and(|b| !b.is_mixed(i)) {
+                    for _ in 0..len {
+                        ch.skip();
+                    }
+                } else if ch.is_muted() {
+                    for _ in 0..len {
+                        ch.next();
+                    }
+                } else {
+                    ch.mix_span(span);
+                }
+            }
+
+            let fgvol = (self.global_volume * self.amplification)
+                / (self.global_volume + self.amplification);
+            for s in span.iter_mut() {
+                *s = (s.0 * fgvol, s.1 * fgvol);
+            }
+            self.generated_samples += len as u64;
+            written += len;
+        }
+        written
     }
 
     /// Returns samples one after the other, starting with the left channel.
@@ -487,14 +1415,180 @@ impl<'a> XmrsPlayer<'a> {
     }
 }
 
-impl<'a> Iterator for XmrsPlayer<'a> {
+impl<'a, const CHANNELS: usize> Iterator for XmrsPlayer<'a, CHANNELS> {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.max_loop_count > 0 && self.loop_count >= self.max_loop_count {
+        if self.is_finished() {
             return None;
         } else {
             self.sample_one()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_module::*;
+
+    /// Output of a centre-panned constant sample, once volume ramps are done
+    fn centre_level(historical: bool, pan_law: PanLaw) -> (f32, f32) {
+        let mut pattern = pattern(4, 1);
+        pattern[0][0] = note(Note::C4, 1);
+        let module = module(vec![dc(16384)], vec![pattern], vec![0]);
+        let mut player = XmrsPlayer::new(&module, 48000.0, historical);
+        player.set_mix_laws(pan_law, player.volume_curve);
+        let mut out = [(0.0, 0.0); 2000];
+        assert_eq!(player.render(&mut out), out.len());
+        out[out.len() - 1]
+    }
+
+    /// Largest difference in LSB between `sample_i16()` and `sample(true)`
+    #[cfg(feature = "fixed")]
+    fn integer_error(module: &Module, gain: f32) -> i32 {
+        let mut float = XmrsPlayer::new(module, 48000.0, false);
+        let mut integer = XmrsPlayer::new(module, 48000.0, false);
+        float.set_max_loop_count(1);
+        integer.set_max_loop_count(1);
+        float.console.set_gain(0, gain);
+        integer.console.set_gain(0, gain);
+        let to_i16 = |v: f32| (v * 32768.0).round().clamp(-32768.0, 32767.0) as i32;
+        let mut error = 0;
+        while let Some((left, right)) = float.sample(true) {
+            let (l, r) = integer.sample_i16().unwrap();
+            error = error
+                .max((l as i32 - to_i16(left)).abs())
+                .max((r as i32 - to_i16(right)).abs());
+        }
+        assert_eq!(integer.sample_i16(), None);
+        error
+    }
+
+    #[cfg(feature = "fixed")]
+    #[test]
+    fn integer_mix_follows_float_mix() {
+        let module = song(4, 2);
+        // About 5 LSB at unity gain, Q15 rounding errors are amplified by the console gain
+        for gain in [1.0, 8.0] {
+            let error = integer_error(&module, gain);
+            assert!(error <= (5.0 * gain) as i32, "gain {}: {} LSB", gain, error);
+        }
+    }
+
+    struct Record<'r>(&'r mut Vec<crate::midi::MidiEvent>);
+
+    impl MidiSink for Record<'_> {
+        fn send(&mut self, _frame: u64, event: crate::midi::MidiEvent) {
+            self.0.push(event);
+        }
+    }
+
+    /// Note-ons received by a sink attached after `ticks` ticks, with a note on the first one
+    fn note_ons_after(ticks: usize) -> usize {
+        let mut pattern = pattern(4, 1);
+        pattern[0][0] = note(Note::C4, 1);
+        let mut module = module(vec![dc(16384)], vec![pattern], vec![0]);
+        if let InstrumentType::Default(instr) = &mut module.instrument[0].instr_type {
+            instr.midi.on = true;
+        }
+        let mut events = vec![];
+        let mut player = XmrsPlayer::new(&module, 48000.0, false);
+        for _ in 0..ticks {
+            player.step_tick();
+        }
+        player.set_midi_sink(Some(Box::new(Record(&mut events))));
+        player.step_tick();
+        drop(player);
+        events
+            .iter()
+            .filter(|e| matches!(e, crate::midi::MidiEvent::NoteOn { .. }))
+            .count()
+    }
+
+    #[test]
+    fn midi_sink_attached_mid_song() {
+        assert_eq!(note_ons_after(0), 1);
+        // The note was triggered before the sink was attached
+        assert_eq!(note_ons_after(1), 0);
+        assert_eq!(note_ons_after(5), 0);
+    }
+
+    #[test]
+    fn centre_panned_level() {
+        // Global volume and amplification at 1.0 give a 0.5 mix gain
+        for (historical, chain) in [(false, 1.0 / 3.0), (true, 1.0)] {
+            for law in [
+                PanLaw::ConstantPower,
+                PanLaw::Linear,
+                PanLaw::Ft2,
+                PanLaw::Amiga,
+            ] {
+                let (left, right) = centre_level(historical, law);
+                let level = 0.5 * chain * 0.5 * law.gains(0.5)[0];
+                assert_eq!(left, right, "{:?}", law);
+                assert!(
+                    (left - level).abs() < 1e-3,
+                    "{:?} historical {}: {} instead of {}",
+                    law,
+                    historical,
+                    left,
+                    level
+                );
+            }
+        }
+    }
+
+    /// Frames of the whole song, played once
+    fn song_frames(module: &Module, interpolate: bool, render: bool) -> Vec<(f32, f32)> {
+        let mut player = XmrsPlayer::new(module, 48000.0, false);
+        player.set_max_loop_count(1);
+        player.set_interpolation(interpolate);
+        let mut frames = vec![];
+        if render {
+            // Blocks shorter and longer than a tick
+            let mut block = vec![(0.0, 0.0); 2500];
+            for len in [700, 2500, 1].into_iter().cycle() {
+                let written = player.render(&mut block[..len]);
+                frames.extend_from_slice(&block[..written]);
+                if written < len {
+                    break;
+                }
+            }
+        } else {
+            while let Some(frame) = player.sample(true) {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn render_is_sample_frame_by_frame() {
+        for flags in [LoopType::Forward, LoopType::PingPong, LoopType::No] {
+            let mut module = song(4, 2);
+            for instrument in &mut module.instrument {
+                if let InstrumentType::Default(instr) = &mut instrument.instr_type {
+                    instr.sample[0].flags = flags;
+                }
+            }
+            for interpolate in [true, false] {
+                let frames = song_frames(&module, interpolate, false);
+                let rendered = song_frames(&module, interpolate, true);
+                assert_eq!(frames.len(), 2 * 16 * 6 * 960);
+                assert_eq!(rendered.len(), frames.len());
+                for (i, (a, b)) in rendered.iter().zip(&frames).enumerate() {
+                    assert!(
+                        a.0.to_bits() == b.0.to_bits() && a.1.to_bits() == b.1.to_bits(),
+                        "{:?} interpolate {}: frame {}: {:?} instead of {:?}",
+                        flags,
+                        interpolate,
+                        i,
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+    }
+}
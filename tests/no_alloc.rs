@@ -0,0 +1,186 @@
+//! Audio paths that must not allocate once built
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use xmrs::prelude::*;
+use xmrsplayer::instrument_sampler::InstrumentSampler;
+use xmrsplayer::xmrsplayer::XmrsPlayer;
+
+/// Counts allocations of the current thread, tests run in parallel
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Allocations made by `f`
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(|a| a.get());
+    f();
+    ALLOCATIONS.with(|a| a.get()) - before
+}
+
+/// Forward looped sine
+fn sample() -> Sample {
+    let data = (0..2000)
+        .map(|i| ((i as f32 * 0.07).sin() * 30000.0) as i16)
+        .collect();
+    Sample {
+        name: "".into(),
+        loop_start: 1000,
+        loop_length: 1000,
+        volume: 1.0,
+        finetune: 0.0,
+        flags: LoopType::Forward,
+        panning: 0.5,
+        relative_note: 0,
+        data: SampleDataType::Mono16(data),
+    }
+}
+
+fn instrument() -> Instrument {
+    let mut instr = InstrDefault::default();
+    instr.sample.push(sample());
+    Instrument {
+        name: "".into(),
+        instr_type: InstrumentType::Default(instr),
+        muted: false,
+    }
+}
+
+/// Two instruments, `channels` channels with notes and effects on every row
+fn module(channels: usize) -> Module {
+    let notes = [Note::C4, Note::E4, Note::G4, Note::C5];
+    let pattern = (0..32)
+        .map(|r| {
+            (0..channels)
+                .map(|c| match (r + c) % 4 {
+                    0 => PatternSlot {
+                        note: notes[(r + c) % notes.len()],
+                        instrument: 1 + (c % 2) as u8,
+                        ..Default::default()
+                    },
+                    1 => PatternSlot {
+                        effect_type: 0x4,
+                        effect_parameter: 0x46,
+                        ..Default::default()
+                    },
+                    2 => PatternSlot {
+                        effect_type: 0xA,
+                        effect_parameter: 0x02,
+                        ..Default::default()
+                    },
+                    _ => PatternSlot {
+                        note: Note::KeyOff,
+                        ..Default::default()
+                    },
+                })
+                .collect()
+        })
+        .collect();
+    Module {
+        frequency_type: FrequencyType::LinearFrequencies,
+        instrument: vec![instrument(), instrument()],
+        pattern: vec![pattern],
+        pattern_order: vec![0, 0],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn instrument_sampler() {
+    let module = module(1);
+    let mut sampler = InstrumentSampler::new(&module, 48000.0, false, 4);
+    let count = allocations(|| {
+        for i in 0..48000 {
+            if i % 4000 == 0 {
+                sampler.note_on(i / 4000 % 2, Note::C4, 1.0);
+            }
+            if i % 4000 == 2000 {
+                sampler.note_off(i / 4000 % 2, Note::C4);
+            }
+            sampler.sample();
+        }
+        let mut block = [(0.0, 0.0); 256];
+        sampler.render(&mut block);
+    });
+    assert_eq!(count, 0);
+}
+
+/// Both song positions and back to the first one
+const SONG_FRAMES: usize = 2 * 32 * 5760 + 10000;
+
+#[test]
+fn player_render() {
+    let module = module(4);
+    let mut player = XmrsPlayer::new(&module, 48000.0, false);
+    let mut block = [(0.0, 0.0); 1000];
+    let count = allocations(|| {
+        for _ in 0..SONG_FRAMES / block.len() {
+            player.render(&mut block);
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn player_sample() {
+    let module = module(4);
+    let mut player = XmrsPlayer::new(&module, 48000.0, false);
+    player.set_scope(256);
+    let mut samples = [(0.0, 0.0); 4];
+    let count = allocations(|| {
+        for i in 0..SONG_FRAMES {
+            match i % 3 {
+                0 => assert!(player.sample(true).is_some()),
+                1 => assert!(player.samples_from_channels_into(&mut samples)),
+                _ => assert!(player.next().is_some()),
+            }
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn fixed_channels() {
+    let module = module(4);
+    let mut heap = XmrsPlayer::new(&module, 48000.0, false);
+    let mut fixed: XmrsPlayer<4> = XmrsPlayer::new_fixed(&module, 48000.0, false);
+    assert_eq!(fixed.channel.len(), 4);
+    let mut expected = vec![(0.0, 0.0); SONG_FRAMES];
+    heap.render(&mut expected);
+    let mut block = [(0.0, 0.0); 1000];
+    let count = allocations(|| {
+        for chunk in expected.chunks(block.len()) {
+            let block = &mut block[..chunk.len()];
+            fixed.render(block);
+            assert_eq!(block, chunk);
+        }
+    });
+    assert_eq!(count, 0);
+
+    // Channels after the second one are not played, extra channels stay silent
+    let mut two: XmrsPlayer<2> = XmrsPlayer::new_fixed(&module, 48000.0, false);
+    let mut eight: XmrsPlayer<8> = XmrsPlayer::new_fixed(&module, 48000.0, false);
+    two.render(&mut block);
+    eight.render(&mut block);
+    assert_eq!(block, expected[..block.len()]);
+}